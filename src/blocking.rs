@@ -0,0 +1,74 @@
+//! A synchronous wrapper around [`SengledApi`] for callers that don't run an async executor.
+//! Each call blocks the current thread until the underlying future completes, so these methods
+//! must not be used from inside an async context.
+
+use crate::{BatchResult, Brightness, Color, Device, Error, Region, Scene, SengledApi};
+use smol::block_on;
+
+#[derive(Clone)]
+pub struct BlockingSengledApi {
+    inner: SengledApi,
+}
+
+impl BlockingSengledApi {
+    pub fn new<T: AsRef<str>, U: AsRef<str>>(user: T, pass: U) -> Result<Self, Error> {
+        block_on(SengledApi::new(user, pass)).map(Self::from)
+    }
+    pub fn new_with_region<T: AsRef<str>, U: AsRef<str>>(
+        user: T,
+        pass: U,
+        region: Region,
+    ) -> Result<Self, Error> {
+        block_on(SengledApi::new_with_region(user, pass, region)).map(Self::from)
+    }
+    pub fn from_session_id(session_id: String, region: Region) -> Result<Self, Error> {
+        block_on(SengledApi::from_session_id(session_id, region)).map(Self::from)
+    }
+    /// The wrapped async client, for anything not covered here.
+    pub fn inner(&self) -> &SengledApi {
+        &self.inner
+    }
+    pub fn into_inner(self) -> SengledApi {
+        self.inner
+    }
+    pub fn get_devices(&self) -> Result<Vec<Device>, Error> {
+        block_on(self.inner.get_devices())
+    }
+    pub fn get_device_by_name(&self, name: &str) -> Result<Option<Device>, Error> {
+        block_on(self.inner.get_device_by_name(name))
+    }
+    pub fn turn_on(&self, device: &Device) -> Result<(), Error> {
+        block_on(self.inner.turn_on(device))
+    }
+    pub fn turn_off(&self, device: &Device) -> Result<(), Error> {
+        block_on(self.inner.turn_off(device))
+    }
+    pub fn toggle(&self, device: &Device) -> Result<bool, Error> {
+        block_on(self.inner.toggle(device))
+    }
+    pub fn set_brightness(&self, device: &Device, brightness: Brightness) -> Result<(), Error> {
+        block_on(self.inner.set_brightness(device, brightness))
+    }
+    pub fn set_color(&self, device: &Device, color: Color) -> Result<(), Error> {
+        block_on(self.inner.set_color(device, color))
+    }
+    pub fn set_color_temperature(&self, device: &Device, kelvin: u16) -> Result<(), Error> {
+        block_on(self.inner.set_color_temperature(device, kelvin))
+    }
+    pub fn apply_scene(&self, scene: &Scene) -> BatchResult {
+        block_on(self.inner.apply_scene(scene))
+    }
+    pub fn turn_off_all(&self) -> Result<BatchResult, Error> {
+        block_on(self.inner.turn_off_all())
+    }
+    /// Like [`SengledApi::disconnect`], only closes the connection if no other clone shares it.
+    pub fn disconnect(self) -> Result<(), Error> {
+        block_on(self.inner.disconnect())
+    }
+}
+
+impl From<SengledApi> for BlockingSengledApi {
+    fn from(inner: SengledApi) -> Self {
+        BlockingSengledApi { inner }
+    }
+}
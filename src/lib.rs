@@ -1,9 +1,51 @@
-use paho_mqtt as mqtt;
-use serde::{Deserialize, Serialize};
-use std::time::{SystemTime, UNIX_EPOCH};
-use std::{convert::TryInto, fmt::Display};
-use surf::Body;
+use futures::{
+    channel::{mpsc, oneshot},
+    future,
+    future::{BoxFuture, Shared},
+    stream, FutureExt, Stream, StreamExt,
+};
+pub use paho_mqtt as mqtt;
+use serde::{ser::SerializeStruct, Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{
+    convert::{Infallible, TryFrom, TryInto},
+    fmt::Display,
+};
 use thiserror::Error;
+use uuid::Uuid;
+
+#[cfg(feature = "blocking")]
+pub mod blocking;
+// The supervisor thread runs on `smol::block_on`, which lacks the reactor tokio-based HTTP
+// backends need.
+#[cfg(not(any(
+    feature = "runtime-tokio",
+    all(feature = "reqwest", not(feature = "surf"))
+)))]
+pub mod reliable;
+
+/// The types most programs need, for a single glob import.
+pub mod prelude {
+    #[cfg(feature = "blocking")]
+    pub use crate::blocking::BlockingSengledApi;
+    #[cfg(not(any(
+        feature = "runtime-tokio",
+        all(feature = "reqwest", not(feature = "surf"))
+    )))]
+    pub use crate::reliable::ReliableSengledApi;
+    pub use crate::{
+        BatchResult, Brightness, Capabilities, Color, Device, DeviceState, Error, Mac, NamedColor,
+        OfflinePolicy, Qos, Region, Room, Scene, SengledApi, SengledApiBuilder,
+    };
+}
 
 struct SengledOsType;
 
@@ -50,278 +92,3557 @@ struct SengledLoginRequest {
 }
 
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum Error {
-    #[error("http error occurred: {0}")]
-    Http(surf::Error),
-    #[error("authentication failed")]
-    AuthenticationFailure,
+    #[error("http transport error occurred: {0}")]
+    Transport(HttpError),
+    #[error("http request failed with status {0}")]
+    HttpStatus(u16),
+    #[error("http request timed out")]
+    Timeout,
+    #[error("invalid MAC address {0:?}, expected six hex bytes")]
+    InvalidMac(String),
+    #[error("HSV ({hue}, {saturation}, {value}) out of range, expected 0–360 and 0–100")]
+    InvalidHsv { hue: u16, saturation: u8, value: u8 },
+    #[error("no device with MAC {0} on this account")]
+    DeviceNotFound(String),
+    #[error("device {0} was offline at the last device listing")]
+    DeviceOffline(String),
+    #[error("brightness must be at most 100%, got {0}")]
+    InvalidBrightness(u8),
+    #[error("device {device} does not support {capability}")]
+    UnsupportedCapability {
+        device: String,
+        capability: &'static str,
+    },
+    #[error("device {device} did not confirm value {expected:?} (last reported {reported:?})")]
+    CommandRejected {
+        device: String,
+        expected: String,
+        reported: Option<String>,
+    },
+    #[error("authentication failed ({code}): {message}")]
+    AuthenticationFailure { code: i32, message: String },
     #[error("serialization error: {0}")]
     Serialization(#[from] serde_json::Error),
     #[error("mqtt error: {0}")]
     Mqtt(#[from] mqtt::Error),
+    #[error("failed to connect to MQTT broker at {uri}: {reason}")]
+    Connect {
+        uri: String,
+        reason: String,
+        #[source]
+        source: mqtt::Error,
+    },
 }
 
-impl From<surf::Error> for Error {
-    fn from(e: surf::Error) -> Self {
-        Error::Http(e)
+/// Lets conversions that can't fail, such as `Mac` into itself, stand in wherever a fallible
+/// one is accepted.
+impl From<Infallible> for Error {
+    fn from(never: Infallible) -> Self {
+        match never {}
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+impl Error {
+    /// Whether the same operation might succeed if tried again: transport failures, timeouts,
+    /// 5xx responses, and MQTT publishes that failed because the client was disconnected or
+    /// backed up. Authentication, serialization and validation errors are never retryable.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::Transport(_) | Error::Timeout => true,
+            Error::HttpStatus(status) => *status >= 500,
+            Error::Mqtt(error) => matches!(
+                error,
+                // MQTTASYNC_DISCONNECTED and MQTTASYNC_MAX_MESSAGES_INFLIGHT
+                mqtt::Error::Paho(-3)
+                    | mqtt::Error::Paho(-4)
+                    | mqtt::Error::PahoDescr(-3, _)
+                    | mqtt::Error::PahoDescr(-4, _)
+                    | mqtt::Error::Timeout
+                    | mqtt::Error::Io(_)
+            ),
+            _ => false,
+        }
+    }
+}
+
+fn connect_error(uri: &str, source: mqtt::Error) -> Error {
+    // Positive return codes are CONNACK refusals from the broker; anything else failed before
+    // the MQTT handshake (TCP, TLS or the WebSocket upgrade), which paho describes itself.
+    let reason = match &source {
+        mqtt::Error::Paho(code) | mqtt::Error::PahoDescr(code, _) if *code > 0 => {
+            format!(
+                "broker refused the connection: {}",
+                match code {
+                    1 => "unacceptable protocol version",
+                    2 => "client identifier rejected",
+                    3 => "server unavailable",
+                    4 => "bad credentials",
+                    5 => "not authorized, the session may have expired",
+                    _ => "unknown reason",
+                }
+            )
+        }
+        source => format!("{}; check the configured region", source),
+    };
+    Error::Connect {
+        uri: uri.into(),
+        reason,
+        source,
+    }
+}
+
+#[cfg(not(any(feature = "surf", feature = "reqwest")))]
+compile_error!(
+    "an HTTP backend is required: enable `runtime-async-std`, `runtime-tokio` or `reqwest`"
+);
+
+// `smol::block_on` doesn't provide the tokio reactor the hyper and reqwest clients need, so
+// blocking calls would panic at runtime.
+#[cfg(all(
+    feature = "blocking",
+    any(
+        feature = "runtime-tokio",
+        all(feature = "reqwest", not(feature = "surf"))
+    )
+))]
+compile_error!("the `blocking` feature only works with the `runtime-async-std` HTTP backend");
+
+#[cfg(feature = "surf")]
+type HttpError = surf::Error;
+#[cfg(all(feature = "reqwest", not(feature = "surf")))]
+type HttpError = reqwest::Error;
+
+#[cfg(feature = "surf")]
+type HttpClient = surf::Client;
+#[cfg(all(feature = "reqwest", not(feature = "surf")))]
+type HttpClient = reqwest::Client;
+
+impl From<HttpError> for Error {
+    fn from(e: HttpError) -> Self {
+        Error::Transport(e)
+    }
+}
+
+/// The Sengled cloud to talk to. Only the US endpoints are known; accounts elsewhere can
+/// supply their own hosts with `Custom`.
+#[derive(Debug, Clone, Default)]
+pub enum Region {
+    #[default]
+    Us,
+    Custom {
+        auth_host: String,
+        life_host: String,
+        mqtt_uri: String,
+    },
+}
+
+impl Region {
+    fn auth_host(&self) -> &str {
+        match self {
+            Region::Us => "ucenter.cloud.sengled.com",
+            Region::Custom { auth_host, .. } => auth_host,
+        }
+    }
+    fn life_host(&self) -> &str {
+        match self {
+            Region::Us => "life2.cloud.sengled.com",
+            Region::Custom { life_host, .. } => life_host,
+        }
+    }
+    fn mqtt_uri(&self) -> &str {
+        match self {
+            Region::Us => "wss://us-mqtt.cloud.sengled.com:443/mqtt",
+            Region::Custom { mqtt_uri, .. } => mqtt_uri,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum Color {
     White { temperature: u32 },
     Rgb { red: u8, green: u8, blue: u8 },
 }
 
-#[derive(Deserialize)]
-#[serde(untagged)]
-enum LoginResponse {
-    Failure,
-    Success {
-        #[serde(rename = "jsessionId")]
-        session_id: String,
-    },
+/// Converts a 0–255 level to the nearest whole percent, so 0 maps to 0, 255 to 100, and
+/// everything in between rounds half away from zero (e.g. 128 becomes 50, 1 becomes 0).
+pub fn u8_to_percent(value: u8) -> u8 {
+    ((value as f32 / 255.) * 100.).round() as u8
 }
 
-#[derive(Debug)]
-pub struct Device {
-    pub name: String,
-    uuid: Mac,
+/// Converts a percentage to the nearest 0–255 level, the inverse of [`u8_to_percent`].
+/// Percentages above 100 are clamped to 255.
+pub fn percent_to_u8(percent: u8) -> u8 {
+    ((percent.min(100) as f32 / 100.) * 255.).round() as u8
 }
 
-impl Device {
-    pub fn uuid(&self) -> [u8; 6] {
-        self.uuid.0
+/// A bulb brightness level. Sengled bulbs work in whole percentages, so values built from the
+/// 0–255 scale are rounded to the nearest percent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Brightness(u8);
+
+impl Brightness {
+    pub fn from_u8(brightness: u8) -> Self {
+        Brightness(u8_to_percent(brightness))
+    }
+    pub fn from_percent(percent: u8) -> Result<Self, Error> {
+        if percent > 100 {
+            return Err(Error::InvalidBrightness(percent));
+        }
+        Ok(Brightness(percent))
+    }
+    pub fn percent(self) -> u8 {
+        self.0
+    }
+    pub fn to_u8(self) -> u8 {
+        percent_to_u8(self.0)
     }
 }
 
-#[derive(Debug, Clone)]
-struct Mac([u8; 6]);
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ParseColorError {
+    #[error("expected 6 hex digits, found {0}")]
+    InvalidLength(usize),
+    #[error("invalid hex digit in color")]
+    InvalidDigit,
+}
 
-impl Display for Mac {
+#[derive(Debug, Clone, Copy)]
+pub enum NamedColor {
+    Warm,
+    Neutral,
+    Cool,
+    Red,
+    Orange,
+    Yellow,
+    Green,
+    Cyan,
+    Blue,
+    Purple,
+    Pink,
+}
+
+impl Color {
+    /// Warm white, 2700K.
+    pub const WARM_WHITE: Color = Color::White { temperature: 2700 };
+    /// Cool white, 6500K.
+    pub const COOL_WHITE: Color = Color::White { temperature: 6500 };
+
+    pub fn from_hex(hex: &str) -> Result<Color, ParseColorError> {
+        let hex = hex.strip_prefix('#').unwrap_or(hex);
+        if hex.len() != 6 {
+            return Err(ParseColorError::InvalidLength(hex.len()));
+        }
+        // from_str_radix accepts a leading sign, so check the digits up front.
+        if !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return Err(ParseColorError::InvalidDigit);
+        }
+        let channel =
+            |range| u8::from_str_radix(&hex[range], 16).map_err(|_| ParseColorError::InvalidDigit);
+        Ok(Color::Rgb {
+            red: channel(0..2)?,
+            green: channel(2..4)?,
+            blue: channel(4..6)?,
+        })
+    }
+    /// Converts from HSV, with `hue` in degrees and `saturation`/`value` in `0.0..=1.0`.
+    /// Out-of-range inputs are wrapped (hue) or clamped (saturation, value).
+    pub fn from_hsv(hue: f32, saturation: f32, value: f32) -> Color {
+        let hue = hue.rem_euclid(360.) / 60.;
+        let saturation = saturation.clamp(0., 1.);
+        let value = value.clamp(0., 1.);
+        let chroma = value * saturation;
+        let x = chroma * (1. - (hue % 2. - 1.).abs());
+        let (red, green, blue) = match hue as u8 {
+            0 => (chroma, x, 0.),
+            1 => (x, chroma, 0.),
+            2 => (0., chroma, x),
+            3 => (0., x, chroma),
+            4 => (x, 0., chroma),
+            _ => (chroma, 0., x),
+        };
+        let m = value - chroma;
+        let scale = |channel: f32| ((channel + m) * 255.).round() as u8;
+        Color::Rgb {
+            red: scale(red),
+            green: scale(green),
+            blue: scale(blue),
+        }
+    }
+    /// Approximates the color as RGB, converting white temperatures along the black-body curve.
+    pub fn to_rgb(self) -> (u8, u8, u8) {
+        match self {
+            Color::Rgb { red, green, blue } => (red, green, blue),
+            Color::White { temperature } => {
+                let t = temperature.clamp(1000, 40000) as f64 / 100.;
+                let red = if t <= 66. {
+                    255.
+                } else {
+                    329.698_727_446 * (t - 60.).powf(-0.133_204_759_2)
+                };
+                let green = if t <= 66. {
+                    99.470_802_586_1 * t.ln() - 161.119_568_166_1
+                } else {
+                    288.122_169_528_3 * (t - 60.).powf(-0.075_514_849_2)
+                };
+                let blue = if t >= 66. {
+                    255.
+                } else if t <= 19. {
+                    0.
+                } else {
+                    138.517_731_223_1 * (t - 10.).ln() - 305.044_792_730_7
+                };
+                let clamp = |channel: f64| channel.clamp(0., 255.).round() as u8;
+                (clamp(red), clamp(green), clamp(blue))
+            }
+        }
+    }
+    fn interpolate(self, to: Color, progress: f64) -> Color {
+        let lerp = |from: f64, to: f64| from + (to - from) * progress;
+        match (self, to) {
+            (Color::White { temperature: from }, Color::White { temperature: to }) => {
+                Color::White {
+                    temperature: lerp(from as f64, to as f64).round() as u32,
+                }
+            }
+            (from, to) => {
+                let (from, to) = (from.to_rgb(), to.to_rgb());
+                let channel = |from: u8, to: u8| lerp(from as f64, to as f64).round() as u8;
+                Color::Rgb {
+                    red: channel(from.0, to.0),
+                    green: channel(from.1, to.1),
+                    blue: channel(from.2, to.2),
+                }
+            }
+        }
+    }
+    pub fn named(color: NamedColor) -> Color {
+        let rgb = |red, green, blue| Color::Rgb { red, green, blue };
+        match color {
+            NamedColor::Warm => Color::WARM_WHITE,
+            NamedColor::Neutral => Color::White { temperature: 4000 },
+            NamedColor::Cool => Color::COOL_WHITE,
+            NamedColor::Red => rgb(255, 0, 0),
+            NamedColor::Orange => rgb(255, 128, 0),
+            NamedColor::Yellow => rgb(255, 255, 0),
+            NamedColor::Green => rgb(0, 255, 0),
+            NamedColor::Cyan => rgb(0, 255, 255),
+            NamedColor::Blue => rgb(0, 0, 255),
+            NamedColor::Purple => rgb(128, 0, 255),
+            NamedColor::Pink => rgb(255, 0, 128),
+        }
+    }
+}
+
+/// Maps screen RGB to what a bulb needs to look the same on the wall. Each channel is
+/// normalized, raised to `gamma`, then scaled by its white balance factor. White color
+/// temperatures pass through untouched.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ColorProfile {
+    pub gamma: f32,
+    pub white_balance: (f32, f32, f32),
+}
+
+impl ColorProfile {
+    /// Leaves colors exactly as given.
+    pub const IDENTITY: ColorProfile = ColorProfile {
+        gamma: 1.,
+        white_balance: (1., 1., 1.),
+    };
+
+    pub fn apply(self, color: Color) -> Color {
+        match color {
+            Color::Rgb { red, green, blue } => {
+                let correct = |channel: u8, balance: f32| {
+                    ((channel as f32 / 255.).powf(self.gamma) * balance.clamp(0., 1.) * 255.)
+                        .round() as u8
+                };
+                Color::Rgb {
+                    red: correct(red, self.white_balance.0),
+                    green: correct(green, self.white_balance.1),
+                    blue: correct(blue, self.white_balance.2),
+                }
+            }
+            white => white,
+        }
+    }
+}
+
+impl Default for ColorProfile {
+    /// Lifts dim channels that the bulbs otherwise crush and pulls back green, which the
+    /// bulbs' LEDs overweight at full white.
+    fn default() -> Self {
+        ColorProfile {
+            gamma: 0.8,
+            white_balance: (1., 0.85, 0.9),
+        }
+    }
+}
+
+impl Display for Color {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}",
-            self.0
-                .iter()
-                .map(|byte| format!("{:X}", byte))
-                .collect::<Vec<_>>()
-                .join(":")
-        )
+        match self {
+            Color::Rgb { red, green, blue } => write!(f, "rgb({},{},{})", red, green, blue),
+            Color::White { temperature } => write!(f, "white({}K)", temperature),
+        }
     }
 }
 
-#[derive(Deserialize, Debug)]
-struct Attribute {
-    name: String,
-    value: String,
+impl FromStr for Color {
+    type Err = ParseColorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Color::from_hex(s)
+    }
 }
 
-#[derive(Deserialize, Debug)]
-#[serde(rename_all = "camelCase")]
-struct RawDeviceResponse {
-    device_uuid: String,
-    attribute_list: Vec<Attribute>,
+const MIN_TEMPERATURE: u32 = 2000;
+const MAX_TEMPERATURE: u32 = 6500;
+
+fn temperature_to_percent(temperature: u32) -> u8 {
+    (((temperature.clamp(MIN_TEMPERATURE, MAX_TEMPERATURE) - MIN_TEMPERATURE) as f64
+        / (MAX_TEMPERATURE - MIN_TEMPERATURE) as f64)
+        * 100.)
+        .round() as u8
 }
 
-impl<'de> Deserialize<'de> for Device {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        let raw = RawDeviceResponse::deserialize(deserializer)?;
-        let name = raw
-            .attribute_list
-            .into_iter()
-            .find(|item| item.name == "name")
-            .ok_or(serde::de::Error::custom("no name field in attributes"))?
-            .value;
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceState {
+    pub on: Option<bool>,
+    pub brightness: Option<Brightness>,
+    pub color: Option<Color>,
+}
 
-        Ok(Device {
-            name,
-            uuid: Mac(raw
-                .device_uuid
-                .split(':')
-                .map(|item| u8::from_str_radix(item, 16))
-                .collect::<Result<Vec<_>, _>>()
-                .map_err(|e| serde::de::Error::custom(format!("invalid UUID: {}", e)))?
-                .as_slice()
-                .try_into()
-                .map_err(|e| serde::de::Error::custom(format!("invalid UUID: {}", e)))?),
-        })
+impl DeviceState {
+    fn commands(&self, device: &Device) -> Vec<Command> {
+        if self.on == Some(false) {
+            return vec![Command::switch(device, false)];
+        }
+        let mut commands = vec![];
+        if self.on == Some(true) {
+            commands.push(Command::switch(device, true));
+        }
+        if let Some(brightness) = self.brightness {
+            commands.push(Command::brightness(device, brightness.percent()));
+        }
+        if let Some(color) = self.color {
+            commands.push(Command::color(device, color));
+        }
+        commands
+    }
+    fn update(&mut self, entries: Vec<StatusEntry>) {
+        for entry in entries {
+            match entry.ty.as_str() {
+                "switch" => self.on = Some(entry.value == "1"),
+                "brightness" => {
+                    if let Ok(percent) = entry.value.parse::<u8>() {
+                        self.brightness = Some(Brightness(percent.min(100)));
+                    }
+                }
+                "color" => {
+                    let channels = entry
+                        .value
+                        .split(':')
+                        .map(|item| item.parse::<u8>())
+                        .collect::<Result<Vec<_>, _>>();
+                    if let Ok([red, green, blue]) = channels.as_deref() {
+                        self.color = Some(Color::Rgb {
+                            red: *red,
+                            green: *green,
+                            blue: *blue,
+                        });
+                    }
+                }
+                "colorTemperature" => {
+                    if let Ok(value) = entry.value.parse::<u32>() {
+                        self.color = Some(Color::White {
+                            temperature: MIN_TEMPERATURE
+                                + (value.min(100) * (MAX_TEMPERATURE - MIN_TEMPERATURE)) / 100,
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
     }
 }
 
-#[derive(Deserialize, Debug)]
-#[serde(rename_all = "camelCase")]
-struct DevicesResponse {
-    device_list: Vec<Device>,
+/// Energy use as reported by the device. Sengled doesn't document units; `power` appears to be
+/// the current draw in watts and `consumption` the running total in kilowatt-hours.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EnergyStats {
+    pub power: Option<f64>,
+    pub consumption: Option<f64>,
 }
 
-pub struct SengledApi {
-    session_id: String,
-    client: mqtt::AsyncClient,
+#[derive(Debug, Clone)]
+pub struct Scene {
+    name: String,
+    members: Vec<(Device, DeviceState)>,
 }
 
-#[derive(Serialize)]
-#[serde(rename_all = "camelCase")]
-enum CommandType {
-    Switch,
-    Brightness,
-    Color,
-    ColorTemperature,
+impl Scene {
+    pub fn new<T: AsRef<str>>(name: T) -> Self {
+        Scene {
+            name: name.as_ref().into(),
+            members: vec![],
+        }
+    }
+    /// Adds `device` to the scene. Fields of `state` left as `None` are not changed when the
+    /// scene is applied.
+    pub fn add(mut self, device: &Device, state: DeviceState) -> Self {
+        self.members.push((device.clone(), state));
+        self
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn members(&self) -> &[(Device, DeviceState)] {
+        &self.members
+    }
 }
 
-#[derive(Serialize)]
-pub struct Command {
+#[derive(Deserialize, Debug, Clone)]
+struct StatusEntry {
     #[serde(rename = "type")]
-    ty: CommandType,
-    dn: Mac,
+    ty: String,
     value: String,
-    time: CurrentTime,
 }
 
-impl Serialize for Mac {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: serde::Serializer,
-    {
-        format!("{}", self).serialize(serializer)
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StatusMessage {
+    Many(Vec<StatusEntry>),
+    One(StatusEntry),
+}
+
+type Subscribers = Arc<Mutex<Vec<(String, mpsc::UnboundedSender<Vec<StatusEntry>>)>>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    Connected,
+    ConnectionLost,
+}
+
+/// The end of a device's lane, which resolves once the send that queued it and every send
+/// before that has finished or been dropped. The token identifies the send it belongs to.
+type Lane = (Arc<()>, Shared<BoxFuture<'static, ()>>);
+type Lanes = Arc<Mutex<HashMap<Mac, Lane>>>;
+
+/// Passes every topic that still has a live subscriber to `subscribe`, once each, dropping
+/// subscribers whose streams are gone. The broker forgets subscriptions across reconnects, so
+/// this runs on every connect.
+fn resubscribe<F: FnOnce(&[String])>(subscribers: &Subscribers, subscribe: F) {
+    let mut topics: Vec<String> = {
+        let mut subscribers = subscribers.lock().unwrap();
+        subscribers.retain(|(_, sender)| !sender.is_closed());
+        subscribers.iter().map(|(topic, _)| topic.clone()).collect()
+    };
+    topics.sort();
+    topics.dedup();
+    if !topics.is_empty() {
+        log::debug!("resubscribing to {} status topics", topics.len());
+        subscribe(&topics);
     }
 }
 
-pub struct CurrentTime;
+/// A send's place in its device's lane. Dropping it lets the next send to the device go ahead,
+/// and forgets the lane if nothing has queued up behind it.
+struct Turn {
+    lanes: Lanes,
+    dn: Mac,
+    token: Arc<()>,
+    _done: oneshot::Sender<()>,
+}
 
-impl Serialize for CurrentTime {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: serde::Serializer,
-    {
-        SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards")
-            .as_millis()
-            .serialize(serializer)
+impl Drop for Turn {
+    fn drop(&mut self) {
+        let mut lanes = self.lanes.lock().unwrap();
+        if matches!(lanes.get(&self.dn), Some((last, _)) if Arc::ptr_eq(last, &self.token)) {
+            lanes.remove(&self.dn);
+        }
     }
 }
 
-impl SengledApi {
-    pub async fn new<T: AsRef<str>, U: AsRef<str>>(user: T, pass: U) -> Result<Self, Error> {
-        match surf::post("https://ucenter.cloud.sengled.com/user/app/customer/v2/AuthenCross.json")
-            .body(Body::from_json(&SengledLoginRequest {
-                user: user.as_ref().into(),
-                pwd: pass.as_ref().into(),
-                os_type: SengledOsType,
-                product_code: SengledProductCode,
-                app_code: SengledProductCode,
-                uuid: SengledUuid,
-            })?)
-            .recv_json()
-            .await?
-        {
-            LoginResponse::Success { session_id } => {
-                let client = mqtt::CreateOptionsBuilder::new()
-                    .client_id(format!("{}@lifeApp", session_id))
-                    .persistence(mqtt::PersistenceType::None)
-                    .server_uri("wss://us-mqtt.cloud.sengled.com:443/mqtt")
-                    .create_client()
-                    .unwrap();
-                client
-                    .connect(
-                        mqtt::ConnectOptionsBuilder::new()
-                            .http_headers(&[
-                                ("Cookie", format!("JSESSIONID={}", session_id).as_str()),
-                                ("X-Requested-With", "com.sengled.life2"),
-                            ])
-                            .ssl_options(mqtt::SslOptionsBuilder::new().finalize())
-                            .finalize(),
-                    )
-                    .await
-                    .unwrap();
-                Ok(SengledApi { session_id, client })
-            }
-            _ => Err(Error::AuthenticationFailure),
-        }
-    }
-    async fn request<S: Serialize, T>(&self, uri: &str, data: Option<&S>) -> Result<T, surf::Error>
-    where
-        for<'de> T: Deserialize<'de>,
-    {
-        let mut request = surf::post(uri);
-        if let Some(data) = data {
-            request = request.body(Body::from_json(data)?);
+type ConnectionListeners = Arc<Mutex<Vec<mpsc::UnboundedSender<ConnectionEvent>>>>;
+
+fn notify(listeners: &ConnectionListeners, event: ConnectionEvent) {
+    let mut listeners = listeners.lock().unwrap();
+    listeners.retain(|sender| sender.unbounded_send(event).is_ok());
+}
+
+async fn login_once(
+    client: &HttpClient,
+    region: &Region,
+    user: &str,
+    pass: &str,
+    timeout: Duration,
+) -> Result<String, Error> {
+    recv_json::<_, LoginResponse>(
+        client,
+        &format!(
+            "https://{}/user/app/customer/v2/AuthenCross.json",
+            region.auth_host()
+        ),
+        Some(&SengledLoginRequest {
+            user: user.into(),
+            pwd: pass.into(),
+            os_type: SengledOsType,
+            product_code: SengledProductCode,
+            app_code: SengledProductCode,
+            uuid: SengledUuid,
+        }),
+        None,
+        timeout,
+    )
+    .await?
+    .session_id()
+}
+
+async fn login(
+    client: &HttpClient,
+    region: &Region,
+    user: &str,
+    pass: &str,
+    timeout: Duration,
+    retry: RetryPolicy,
+) -> Result<String, Error> {
+    let mut attempt = 1;
+    loop {
+        match login_once(client, region, user, pass, timeout).await {
+            Ok(session_id) => {
+                log::debug!("logged in to Sengled as {}", user);
+                log::trace!("session id {}", session_id);
+                return Ok(session_id);
+            }
+            // A garbled response body is worth another try when logging in.
+            Err(e)
+                if attempt < retry.max_attempts
+                    && (e.is_retryable() || matches!(e, Error::Serialization(_))) =>
+            {
+                log::warn!(
+                    "Sengled login failed (attempt {}/{}): {}",
+                    attempt,
+                    retry.max_attempts,
+                    e
+                );
+                smol::Timer::after(retry.delay(attempt)).await;
+                attempt += 1;
+            }
+            Err(e) => {
+                log::warn!("Sengled login failed: {}", e);
+                return Err(e);
+            }
         }
-        request = request.header("Cookie", format!("JSESSIONID={}", self.session_id));
-        request.recv_json().await
     }
-    async fn send_command(&self, command: &Command) -> Result<(), Error> {
-        self.client
-            .publish(
-                mqtt::MessageBuilder::new()
-                    .topic(format!("wifielement/{}/update", command.dn))
-                    .payload(serde_json::to_string(command)?)
-                    .finalize(),
-            )
-            .await?;
-        Ok(())
+}
+
+/// A Sengled session token along with when it was obtained. Sengled doesn't say when a
+/// session ends, so expiry is estimated from an assumed lifetime.
+#[derive(Debug, Clone)]
+pub struct SessionId {
+    token: String,
+    created_at: Instant,
+    lifetime: Duration,
+}
+
+impl SessionId {
+    /// Wraps `token`, treating it as obtained now and valid for `lifetime`.
+    pub fn new<T: AsRef<str>>(token: T, lifetime: Duration) -> Self {
+        SessionId {
+            token: token.as_ref().into(),
+            created_at: Instant::now(),
+            lifetime,
+        }
     }
-    pub async fn get_devices(&self) -> Result<Vec<Device>, Error> {
-        let resp: DevicesResponse = self
-            .request(
-                "https://life2.cloud.sengled.com/life2/device/list.json",
-                None::<&()>,
-            )
-            .await?;
-        Ok(resp.device_list)
+    pub fn token(&self) -> &str {
+        &self.token
     }
-    pub async fn turn_on(&self, device: &Device) -> Result<(), Error> {
-        self.send_command(&Command {
-            dn: device.uuid.clone(),
-            ty: CommandType::Switch,
-            value: "1".into(),
-            time: CurrentTime,
-        })
-        .await
+    pub fn created_at(&self) -> Instant {
+        self.created_at
     }
-    pub async fn turn_off(&self, device: &Device) -> Result<(), Error> {
-        self.send_command(&Command {
-            dn: device.uuid.clone(),
-            ty: CommandType::Switch,
-            value: "0".into(),
-            time: CurrentTime,
-        })
-        .await
+    pub fn lifetime(&self) -> Duration {
+        self.lifetime
     }
-    pub async fn set_brightness(&self, device: &Device, brightness: u8) -> Result<(), Error> {
-        self.send_command(&Command {
-            dn: device.uuid.clone(),
-            ty: CommandType::Brightness,
-            value: format!("{}", ((brightness as f32 / 255.) * 100.) as u8),
-            time: CurrentTime,
-        })
-        .await
+    pub fn is_likely_expired(&self) -> bool {
+        self.created_at.elapsed() >= self.lifetime
     }
-    pub async fn set_color(&self, device: &Device, color: Color) -> Result<(), Error> {
-        self.send_command(&Command {
-            dn: device.uuid.clone(),
-            ty: if let Color::White { .. } = color {
-                CommandType::ColorTemperature
-            } else {
-                CommandType::Color
-            },
-            value: match color {
-                Color::Rgb { red, green, blue } => {
-                    format!("{}:{}:{}", red, green, blue)
-                }
-                Color::White { temperature } => {
-                    format!("{}", {
-                        (1. + (((temperature.min(6500) - 200) as f64) / 6300.) * 99.) as u8
+}
+
+/// Sengled reports the login outcome in `ret`, which is 0 on success. The session ID is only
+/// trusted when that code says the login went through; bodies without a `ret` count as a
+/// success if they carry a non-empty session ID.
+#[derive(Deserialize)]
+struct LoginResponse {
+    #[serde(default)]
+    ret: Option<i32>,
+    #[serde(default)]
+    msg: String,
+    #[serde(rename = "jsessionId", default)]
+    session_id: Option<String>,
+}
+
+impl LoginResponse {
+    fn session_id(self) -> Result<String, Error> {
+        match self.session_id {
+            Some(session_id) if self.ret.unwrap_or(0) == 0 && !session_id.is_empty() => {
+                Ok(session_id)
+            }
+            _ => Err(Error::AuthenticationFailure {
+                code: self.ret.unwrap_or(-1),
+                message: self.msg,
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Device {
+    pub name: String,
+    pub type_code: Option<String>,
+    pub product_code: Option<String>,
+    pub online: bool,
+    pub room: Option<String>,
+    pub firmware_version: Option<String>,
+    uuid: Mac,
+    color_capable: bool,
+    attributes: HashMap<String, String>,
+}
+
+impl Device {
+    pub fn uuid(&self) -> Mac {
+        self.uuid.clone()
+    }
+    pub fn mac_string(&self) -> String {
+        self.uuid.to_string()
+    }
+    /// The hardware model, which Sengled reports as the type code.
+    pub fn model(&self) -> Option<&str> {
+        self.type_code.as_deref()
+    }
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            color: self.color_capable,
+            tunable_white: self.attributes.contains_key("colorTemperature"),
+            dimmable: self.attributes.contains_key("brightness"),
+        }
+    }
+    pub fn supports_color(&self) -> bool {
+        self.color_capable
+    }
+    pub fn attributes(&self) -> &HashMap<String, String> {
+        &self.attributes
+    }
+    /// Energy figures from the device's `power` and `consumption` attributes, or `None` if it
+    /// reports neither.
+    pub fn energy_stats(&self) -> Option<EnergyStats> {
+        let number = |name| {
+            self.attributes
+                .get(name)
+                .and_then(|value| value.parse::<f64>().ok())
+        };
+        let stats = EnergyStats {
+            power: number("power"),
+            consumption: number("consumption"),
+        };
+        if stats.power.is_none() && stats.consumption.is_none() {
+            None
+        } else {
+            Some(stats)
+        }
+    }
+    /// Snapshot of the device state as reported when the device list was fetched.
+    pub fn state(&self) -> DeviceState {
+        // Both color attributes may be present; apply the one matching the active color mode
+        // last so it wins.
+        let color_attributes = if self.attributes.get("colorMode").map(String::as_str) == Some("2")
+        {
+            ["color", "colorTemperature"]
+        } else {
+            ["colorTemperature", "color"]
+        };
+        let mut state = DeviceState::default();
+        state.update(
+            ["switch", "brightness"]
+                .iter()
+                .chain(color_attributes.iter())
+                .filter_map(|name| {
+                    self.attributes.get(*name).map(|value| StatusEntry {
+                        ty: name.to_string(),
+                        value: value.clone(),
                     })
-                }
-            },
-            time: CurrentTime,
-        })
-        .await
+                })
+                .collect(),
+        );
+        state
+    }
+}
+
+impl Display for Device {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.name, self.uuid)
+    }
+}
+
+impl PartialEq for Device {
+    fn eq(&self, other: &Self) -> bool {
+        self.uuid == other.uuid
+    }
+}
+
+impl Eq for Device {}
+
+impl Hash for Device {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.uuid.hash(state);
+    }
+}
+
+/// What a device can do, inferred from the state attributes it reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    pub color: bool,
+    pub tunable_white: bool,
+    pub dimmable: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Room {
+    pub name: String,
+    pub devices: Vec<Device>,
+}
+
+/// The per-device outcome of an operation applied to several devices at once. A failure on one
+/// device never stops the others from being attempted. Devices are identified by `Device`, or
+/// by `Mac` where only the address is known.
+#[derive(Debug)]
+pub struct BatchResult<T = Device> {
+    results: Vec<(T, Result<(), Error>)>,
+}
+
+impl<T> BatchResult<T> {
+    pub fn results(&self) -> &[(T, Result<(), Error>)] {
+        &self.results
+    }
+    pub fn into_results(self) -> Vec<(T, Result<(), Error>)> {
+        self.results
+    }
+    pub fn successes(&self) -> impl Iterator<Item = &T> {
+        self.results
+            .iter()
+            .filter(|(_, result)| result.is_ok())
+            .map(|(device, _)| device)
+    }
+    pub fn failures(&self) -> impl Iterator<Item = (&T, &Error)> {
+        self.results
+            .iter()
+            .filter_map(|(device, result)| result.as_ref().err().map(|error| (device, error)))
+    }
+    pub fn is_success(&self) -> bool {
+        self.results.iter().all(|(_, result)| result.is_ok())
+    }
+}
+
+/// The difference between two device listings, as returned by `SengledApi::refresh_devices`.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceChanges {
+    pub added: Vec<Device>,
+    pub removed: Vec<Device>,
+    pub changed: Vec<Device>,
+}
+
+impl DeviceChanges {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// A device MAC address, which Sengled uses as the device ID. Parses from six hex bytes,
+/// either colon-separated (`B0:CE:18:00:00:01`) or run together (`b0ce18000001`), in either
+/// case, and displays in the uppercase colon-separated form.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Mac([u8; 6]);
+
+impl Mac {
+    pub fn bytes(&self) -> [u8; 6] {
+        self.0
+    }
+}
+
+impl From<[u8; 6]> for Mac {
+    fn from(bytes: [u8; 6]) -> Self {
+        Mac(bytes)
+    }
+}
+
+impl From<Mac> for [u8; 6] {
+    fn from(mac: Mac) -> Self {
+        mac.0
+    }
+}
+
+impl From<&Mac> for Mac {
+    fn from(mac: &Mac) -> Self {
+        mac.clone()
+    }
+}
+
+impl TryFrom<&str> for Mac {
+    type Error = Error;
+
+    fn try_from(mac: &str) -> Result<Self, Self::Error> {
+        mac.parse()
+    }
+}
+
+impl Display for Mac {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            self.0
+                .iter()
+                .map(|byte| format!("{:02X}", byte))
+                .collect::<Vec<_>>()
+                .join(":")
+        )
+    }
+}
+
+impl FromStr for Mac {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidMac(s.into());
+        let parts: Vec<&str> = if s.contains(':') {
+            s.split(':').collect()
+        } else if s.len() == 12 && s.is_ascii() {
+            (0..12).step_by(2).map(|i| &s[i..i + 2]).collect()
+        } else {
+            return Err(invalid());
+        };
+        let bytes = parts
+            .into_iter()
+            .map(|part| {
+                if part.is_empty() || part.len() > 2 || !part.bytes().all(|b| b.is_ascii_hexdigit())
+                {
+                    return Err(invalid());
+                }
+                u8::from_str_radix(part, 16).map_err(|_| invalid())
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Mac(bytes.as_slice().try_into().map_err(|_| invalid())?))
+    }
+}
+
+impl<'de> Deserialize<'de> for Mac {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Attribute {
+    name: String,
+    value: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct RawDeviceResponse {
+    device_uuid: Mac,
+    attribute_list: Vec<Attribute>,
+}
+
+/// Writes the device in the shape Sengled's device list uses, so it reads back through
+/// `Deserialize` unchanged. The public fields take precedence over the raw attributes.
+impl Serialize for Device {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let mut attributes = self.attributes.clone();
+        let mut set = |name: &str, value: Option<&String>| match value {
+            Some(value) => {
+                attributes.insert(name.into(), value.clone());
+            }
+            None => {
+                attributes.remove(name);
+            }
+        };
+        set("name", Some(&self.name));
+        set("typeCode", self.type_code.as_ref());
+        set("productCode", self.product_code.as_ref());
+        set("roomName", self.room.as_ref());
+        set("version", self.firmware_version.as_ref());
+        attributes.insert("online".into(), if self.online { "1" } else { "0" }.into());
+        let mut attribute_list = attributes
+            .into_iter()
+            .map(|(name, value)| Attribute { name, value })
+            .collect::<Vec<_>>();
+        attribute_list.sort_by(|a, b| a.name.cmp(&b.name));
+        RawDeviceResponse {
+            device_uuid: self.uuid.clone(),
+            attribute_list,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Device {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let raw = RawDeviceResponse::deserialize(deserializer)?;
+        let attributes = raw
+            .attribute_list
+            .into_iter()
+            .map(|item| (item.name, item.value))
+            .collect::<HashMap<_, _>>();
+        let attribute = |name: &str| attributes.get(name).cloned();
+        let uuid = raw.device_uuid;
+        // Freshly paired bulbs may not have a name yet; fall back to the MAC rather than
+        // failing the whole device list.
+        let name = attribute("name")
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| uuid.to_string());
+
+        Ok(Device {
+            name,
+            type_code: attribute("typeCode"),
+            product_code: attribute("productCode"),
+            online: matches!(attribute("online").as_deref(), None | Some("1")),
+            room: attribute("roomName").filter(|room| !room.is_empty()),
+            firmware_version: attribute("version"),
+            color_capable: attribute("color").is_some(),
+            attributes,
+            uuid,
+        })
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct DevicesResponse {
+    device_list: Vec<Device>,
+}
+
+/// A connection to the Sengled cloud. Clones share the same MQTT connection, which is closed
+/// once the last clone is dropped.
+#[derive(Clone)]
+pub struct SengledApi {
+    mqtt_pending: Arc<futures::lock::Mutex<Option<mqtt::ConnectOptions>>>,
+    mqtt_options: Arc<MqttOptions>,
+    relogin: Arc<futures::lock::Mutex<()>>,
+    session: Arc<Mutex<SessionId>>,
+    session_id: String,
+    credentials: Option<Arc<(String, String)>>,
+    login_retry: RetryPolicy,
+    client: mqtt::AsyncClient,
+    _connection: Arc<DisconnectOnDrop>,
+    sink: Arc<dyn CommandSink>,
+    subscribers: Subscribers,
+    connection_listeners: ConnectionListeners,
+    region: Region,
+    retry: RetryPolicy,
+    qos: Qos,
+    fade_step: Duration,
+    min_command_interval: Option<Duration>,
+    http_timeout: Duration,
+    last_sent: Arc<Mutex<HashMap<Mac, Instant>>>,
+    known_devices: Arc<Mutex<HashMap<Mac, Device>>>,
+    sent_state: Arc<Mutex<HashMap<Mac, DeviceState>>>,
+    online: Arc<Mutex<HashMap<Mac, bool>>>,
+    offline_policy: OfflinePolicy,
+    clock: Arc<dyn Clock>,
+    batch: Arc<Mutex<Option<Vec<Command>>>>,
+    history: Option<Arc<CommandHistory>>,
+    color_profile: Option<ColorProfile>,
+    http_client: HttpClient,
+    topic_template: String,
+    status_topic_template: String,
+    verify_colors: Option<Duration>,
+    lanes: Lanes,
+}
+
+/// Everything the MQTT connect options are built from except the session, which the broker
+/// cookie carries and which changes on every login.
+struct MqttOptions {
+    ssl_options: mqtt::SslOptions,
+    keep_alive_interval: Duration,
+    username: Option<String>,
+    password: Option<String>,
+    automatic_reconnect: Option<(Duration, Duration)>,
+}
+
+impl MqttOptions {
+    fn connect_options(&self, session_id: &str) -> mqtt::ConnectOptions {
+        let mut options = mqtt::ConnectOptionsBuilder::new();
+        options
+            .ssl_options(self.ssl_options.clone())
+            .keep_alive_interval(self.keep_alive_interval);
+        match &self.username {
+            Some(username) => {
+                options
+                    .http_headers(&[("X-Requested-With", "com.sengled.life2")])
+                    .user_name(username.as_str());
+                if let Some(password) = &self.password {
+                    options.password(password.as_str());
+                }
+            }
+            None => {
+                options.http_headers(&[
+                    ("Cookie", format!("JSESSIONID={}", session_id).as_str()),
+                    ("X-Requested-With", "com.sengled.life2"),
+                ]);
+            }
+        }
+        if let Some((min_retry_interval, max_retry_interval)) = self.automatic_reconnect {
+            options.automatic_reconnect(min_retry_interval, max_retry_interval);
+        }
+        options.finalize()
+    }
+}
+
+struct DisconnectOnDrop(mqtt::AsyncClient);
+
+impl Drop for DisconnectOnDrop {
+    fn drop(&mut self) {
+        if self.0.is_connected() {
+            drop(self.0.disconnect(None));
+        }
+    }
+}
+
+/// MQTT delivery guarantee for published commands. Higher levels wait on broker
+/// acknowledgements, adding a round trip (or two, for `ExactlyOnce`) to every command.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum Qos {
+    #[default]
+    AtMostOnce,
+    AtLeastOnce,
+    ExactlyOnce,
+}
+
+impl From<Qos> for i32 {
+    fn from(qos: Qos) -> Self {
+        match qos {
+            Qos::AtMostOnce => mqtt::QOS_0,
+            Qos::AtLeastOnce => mqtt::QOS_1,
+            Qos::ExactlyOnce => mqtt::QOS_2,
+        }
+    }
+}
+
+/// What to do when a command targets a device that the last `get_devices` listed as offline.
+/// Such commands are otherwise silently dropped by the broker.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OfflinePolicy {
+    /// Publish without checking.
+    Ignore,
+    /// Log a warning and publish anyway.
+    #[default]
+    Warn,
+    /// Fail with `Error::DeviceOffline` without publishing.
+    Error,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct RetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// How long to wait before retrying after `attempt` (counting from 1) failed.
+    fn delay(&self, attempt: u32) -> Duration {
+        self.base_delay
+            .saturating_mul(2u32.saturating_pow(attempt - 1))
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+#[non_exhaustive]
+pub enum CommandType {
+    Switch,
+    Brightness,
+    Color,
+    ColorTemperature,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum CommandBody {
+    Switch(bool),
+    Brightness(u8),
+    Color(u8, u8, u8),
+    ColorTemperature(u8),
+}
+
+impl CommandBody {
+    fn color(color: Color) -> Self {
+        match color {
+            Color::Rgb { red, green, blue } => CommandBody::Color(red, green, blue),
+            Color::White { temperature } => {
+                CommandBody::ColorTemperature(temperature_to_percent(temperature))
+            }
+        }
+    }
+    /// The body as the status entry a bulb would report once it's applied.
+    fn status(self) -> StatusEntry {
+        StatusEntry {
+            ty: match self.ty() {
+                CommandType::Switch => "switch",
+                CommandType::Brightness => "brightness",
+                CommandType::Color => "color",
+                CommandType::ColorTemperature => "colorTemperature",
+            }
+            .into(),
+            value: self.value(),
+        }
+    }
+    fn ty(self) -> CommandType {
+        match self {
+            CommandBody::Switch(_) => CommandType::Switch,
+            CommandBody::Brightness(_) => CommandType::Brightness,
+            CommandBody::Color(..) => CommandType::Color,
+            CommandBody::ColorTemperature(_) => CommandType::ColorTemperature,
+        }
+    }
+    fn value(self) -> String {
+        match self {
+            CommandBody::Switch(on) => if on { "1" } else { "0" }.into(),
+            CommandBody::Brightness(percent) | CommandBody::ColorTemperature(percent) => {
+                format!("{}", percent.min(100))
+            }
+            CommandBody::Color(red, green, blue) => format!("{}:{}:{}", red, green, blue),
+        }
+    }
+}
+
+/// A command recorded by the history buffer enabled with
+/// `SengledApiBuilder::command_history`.
+#[derive(Debug, Clone)]
+pub struct SentCommand {
+    pub sent_at: Instant,
+    pub device: Mac,
+    pub ty: CommandType,
+    pub value: String,
+}
+
+struct CommandHistory {
+    capacity: usize,
+    entries: Mutex<VecDeque<SentCommand>>,
+}
+
+impl CommandHistory {
+    fn new(capacity: usize) -> Option<Arc<Self>> {
+        if capacity == 0 {
+            return None;
+        }
+        Some(Arc::new(CommandHistory {
+            capacity,
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }))
+    }
+    fn record(&self, commands: &[Command]) {
+        let sent_at = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        for command in commands {
+            if entries.len() == self.capacity {
+                entries.pop_front();
+            }
+            entries.push_back(SentCommand {
+                sent_at,
+                device: command.dn.clone(),
+                ty: command.body.ty(),
+                value: command.body.value(),
+            });
+        }
+    }
+}
+
+const DEFAULT_TOPIC_TEMPLATE: &str = "wifielement/{mac}/update";
+const DEFAULT_STATUS_TOPIC_TEMPLATE: &str = "wifielement/{mac}/status";
+
+fn device_topic(template: &str, dn: &Mac) -> String {
+    template.replace("{mac}", &dn.to_string())
+}
+
+#[derive(Clone)]
+pub struct Command {
+    dn: Mac,
+    body: CommandBody,
+    time: Option<u64>,
+}
+
+/// A command serialized with `time` taken from the command if fixed, or `clock` otherwise.
+struct Stamped<'a> {
+    command: &'a Command,
+    clock: &'a dyn Clock,
+}
+
+impl Serialize for Stamped<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let body = self.command.body;
+        let mut command = serializer.serialize_struct("Command", 4)?;
+        command.serialize_field("type", &body.ty())?;
+        command.serialize_field("dn", &self.command.dn)?;
+        command.serialize_field("value", &body.value())?;
+        command.serialize_field(
+            "time",
+            &self.command.time.unwrap_or_else(|| self.clock.now_millis()),
+        )?;
+        command.end()
+    }
+}
+
+impl Serialize for Command {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        Stamped {
+            command: self,
+            clock: &CurrentTime,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl Command {
+    fn new(device: &Device, body: CommandBody) -> Self {
+        Command::for_mac(device.uuid.clone(), body)
+    }
+    fn for_mac(dn: Mac, body: CommandBody) -> Self {
+        Command {
+            dn,
+            body,
+            time: None,
+        }
+    }
+    pub fn switch(device: &Device, on: bool) -> Self {
+        Command::new(device, CommandBody::Switch(on))
+    }
+    pub fn brightness(device: &Device, percent: u8) -> Self {
+        Command::new(device, CommandBody::Brightness(percent.min(100)))
+    }
+    pub fn color_temperature(device: &Device, percent: u8) -> Self {
+        Command::new(device, CommandBody::ColorTemperature(percent.min(100)))
+    }
+    /// The MQTT topic this command is published on with the default topic template. See
+    /// `SengledApi::command_topic` for an API's configured template.
+    pub fn topic(&self) -> String {
+        device_topic(DEFAULT_TOPIC_TEMPLATE, &self.dn)
+    }
+    /// Fixes the `time` field to `millis` since the Unix epoch instead of the time it's sent,
+    /// for replaying recorded commands.
+    pub fn at(mut self, millis: u64) -> Self {
+        self.time = Some(millis);
+        self
+    }
+    /// The JSON payload this command is published as, with `time` set to now unless fixed
+    /// with `at`. See `SengledApi::command_json` for an API's clock.
+    pub fn to_json(&self) -> Result<String, Error> {
+        Ok(serde_json::to_string(self)?)
+    }
+    pub fn color(device: &Device, color: Color) -> Self {
+        Command::new(device, CommandBody::color(color))
+    }
+}
+
+impl Serialize for Mac {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        format!("{}", self).serialize(serializer)
+    }
+}
+
+/// A pending command publish. Resolves once the broker has accepted the message to the extent
+/// required by the configured `Qos`.
+pub struct Delivery(BoxFuture<'static, Result<(), Error>>);
+
+impl Future for Delivery {
+    type Output = Result<(), Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.0.as_mut().poll(cx)
+    }
+}
+
+/// Destination for serialized commands. The publish should be started when `publish` is called;
+/// the returned future resolves once it has been delivered.
+pub trait CommandSink: Send + Sync {
+    fn publish(
+        &self,
+        topic: String,
+        payload: String,
+        qos: Qos,
+    ) -> BoxFuture<'static, Result<(), Error>>;
+}
+
+impl CommandSink for mqtt::AsyncClient {
+    fn publish(
+        &self,
+        topic: String,
+        payload: String,
+        qos: Qos,
+    ) -> BoxFuture<'static, Result<(), Error>> {
+        let token = mqtt::AsyncClient::publish(
+            self,
+            mqtt::MessageBuilder::new()
+                .topic(topic)
+                .payload(payload)
+                .qos(qos.into())
+                .finalize(),
+        );
+        Box::pin(async move { Ok(token.await?) })
+    }
+}
+
+/// A `CommandSink` that records every publish instead of sending it, for testing command
+/// construction without a broker. Clones share the same record.
+#[derive(Debug, Clone, Default)]
+pub struct MockSink {
+    published: Arc<Mutex<Vec<(String, String)>>>,
+}
+
+impl MockSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// The `(topic, payload)` pairs published so far, in order.
+    pub fn published(&self) -> Vec<(String, String)> {
+        self.published.lock().unwrap().clone()
+    }
+}
+
+impl CommandSink for MockSink {
+    fn publish(
+        &self,
+        topic: String,
+        payload: String,
+        _: Qos,
+    ) -> BoxFuture<'static, Result<(), Error>> {
+        self.published.lock().unwrap().push((topic, payload));
+        Box::pin(future::ready(Ok(())))
+    }
+}
+
+struct DryRunSink;
+
+impl CommandSink for DryRunSink {
+    fn publish(
+        &self,
+        topic: String,
+        payload: String,
+        _: Qos,
+    ) -> BoxFuture<'static, Result<(), Error>> {
+        log::info!("dry run, not publishing to {}: {}", topic, payload);
+        Box::pin(future::ready(Ok(())))
+    }
+}
+
+/// The source of the `time` field in command payloads, in milliseconds since the Unix epoch.
+/// Inject a fixed clock with `SengledApiBuilder::clock` for deterministic payloads.
+pub trait Clock: Send + Sync {
+    fn now_millis(&self) -> u64;
+}
+
+/// The system clock, used unless another `Clock` is configured.
+pub struct CurrentTime;
+
+impl Clock for CurrentTime {
+    fn now_millis(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64
+    }
+}
+
+impl Serialize for CurrentTime {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        self.now_millis().serialize(serializer)
+    }
+}
+
+/// Sends a POST with an optional JSON body and session cookie, returning the response status
+/// and body bytes.
+#[cfg(feature = "surf")]
+async fn post(
+    client: &HttpClient,
+    uri: &str,
+    body: Option<Vec<u8>>,
+    cookie: Option<String>,
+) -> Result<(u16, Vec<u8>), Error> {
+    let mut request = client.post(uri);
+    if let Some(body) = body {
+        request = request.body(body).content_type(surf::http::mime::JSON);
+    }
+    if let Some(cookie) = cookie {
+        request = request.header("Cookie", cookie);
+    }
+    let mut response = request.await?;
+    Ok((response.status().into(), response.body_bytes().await?))
+}
+
+#[cfg(all(feature = "reqwest", not(feature = "surf")))]
+async fn post(
+    client: &HttpClient,
+    uri: &str,
+    body: Option<Vec<u8>>,
+    cookie: Option<String>,
+) -> Result<(u16, Vec<u8>), Error> {
+    let mut request = client.post(uri);
+    if let Some(body) = body {
+        request = request
+            .body(body)
+            .header(reqwest::header::CONTENT_TYPE, "application/json");
+    }
+    if let Some(cookie) = cookie {
+        request = request.header(reqwest::header::COOKIE, cookie);
+    }
+    let response = request.send().await?;
+    Ok((response.status().as_u16(), response.bytes().await?.to_vec()))
+}
+
+async fn recv_json<S: Serialize, T>(
+    client: &HttpClient,
+    uri: &str,
+    body: Option<&S>,
+    cookie: Option<String>,
+    timeout: Duration,
+) -> Result<T, Error>
+where
+    for<'de> T: Deserialize<'de>,
+{
+    let body = body.map(serde_json::to_vec).transpose()?;
+    smol::future::or(
+        async {
+            let (status, body) = post(client, uri, body, cookie).await?;
+            if !(200..300).contains(&status) {
+                return Err(Error::HttpStatus(status));
+            }
+            Ok(serde_json::from_slice(&body)?)
+        },
+        async {
+            smol::Timer::after(timeout).await;
+            Err(Error::Timeout)
+        },
+    )
+    .await
+}
+
+pub struct SengledApiBuilder {
+    user: String,
+    pass: String,
+    region: Region,
+    keep_alive_interval: Duration,
+    automatic_reconnect: Option<(Duration, Duration)>,
+    ssl_options: Option<mqtt::SslOptions>,
+    ca_file: Option<PathBuf>,
+    verify_certificates: bool,
+    client_id: ClientId,
+    retry: RetryPolicy,
+    login_retry: RetryPolicy,
+    qos: Qos,
+    fade_step: Duration,
+    min_command_interval: Option<Duration>,
+    http_timeout: Duration,
+    command_history: usize,
+    color_profile: Option<ColorProfile>,
+    mqtt_username: Option<String>,
+    mqtt_password: Option<String>,
+    dry_run: bool,
+    http_client: HttpClient,
+    topic_template: String,
+    status_topic_template: String,
+    session_lifetime: Duration,
+    offline_policy: OfflinePolicy,
+    persistence: mqtt::PersistenceType,
+    clock: Arc<dyn Clock>,
+    verify_colors: Option<Duration>,
+}
+
+enum ClientId {
+    Suffix(String),
+    Custom(String),
+}
+
+impl ClientId {
+    fn resolve(self, session_id: &str) -> String {
+        match self {
+            ClientId::Suffix(suffix) => format!("{}@lifeApp-{}", session_id, suffix),
+            ClientId::Custom(id) => id,
+        }
+    }
+}
+
+impl SengledApiBuilder {
+    pub fn region(mut self, region: Region) -> Self {
+        self.region = region;
+        self
+    }
+    pub fn keep_alive_interval(mut self, interval: Duration) -> Self {
+        self.keep_alive_interval = interval;
+        self
+    }
+    /// Reconnects automatically when the MQTT connection drops, backing off from
+    /// `min_retry_interval` up to `max_retry_interval` between attempts. Enabled by default with
+    /// a 1s–60s backoff.
+    pub fn automatic_reconnect(
+        mut self,
+        min_retry_interval: Duration,
+        max_retry_interval: Duration,
+    ) -> Self {
+        self.automatic_reconnect = Some((min_retry_interval, max_retry_interval));
+        self
+    }
+    pub fn disable_automatic_reconnect(mut self) -> Self {
+        self.automatic_reconnect = None;
+        self
+    }
+    /// Uses `options` for the MQTT TLS connection as-is, ignoring `ca_file` and
+    /// `danger_accept_invalid_certs`.
+    pub fn ssl_options(mut self, options: mqtt::SslOptions) -> Self {
+        self.ssl_options = Some(options);
+        self
+    }
+    /// Verifies the broker against the CA certificates in the PEM file at `path` rather than the
+    /// system roots.
+    pub fn ca_file<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.ca_file = Some(path.as_ref().into());
+        self
+    }
+    /// Skips verification of the broker's certificate. Only meant for testing.
+    pub fn danger_accept_invalid_certs(mut self) -> Self {
+        self.verify_certificates = false;
+        self
+    }
+    /// Appends `suffix` to the default `<session>@lifeApp` client ID. By default a random suffix
+    /// is used so that several processes sharing an account don't collide on the broker.
+    pub fn client_id_suffix<T: AsRef<str>>(mut self, suffix: T) -> Self {
+        self.client_id = ClientId::Suffix(suffix.as_ref().into());
+        self
+    }
+    pub fn client_id<T: AsRef<str>>(mut self, id: T) -> Self {
+        self.client_id = ClientId::Custom(id.as_ref().into());
+        self
+    }
+    /// Retries publishes that fail with a retryable error (see `Error::is_retryable`) up to
+    /// `max_attempts` times in total, doubling the delay after each failure starting from
+    /// `base_delay`. Defaults to 3 attempts from 250ms; pass 1 to disable retrying.
+    pub fn retry(mut self, max_attempts: u32, base_delay: Duration) -> Self {
+        self.retry = RetryPolicy {
+            max_attempts: max_attempts.max(1),
+            base_delay,
+        };
+        self
+    }
+    /// Retries the login request up to `max_attempts` times in total when it fails with a
+    /// transport error, a timeout, a 5xx status or an unreadable response, doubling the delay
+    /// from `base_delay` each time. Rejected credentials are never retried. Defaults to 3
+    /// attempts from 1 second.
+    pub fn login_retry(mut self, max_attempts: u32, base_delay: Duration) -> Self {
+        self.login_retry = RetryPolicy {
+            max_attempts: max_attempts.max(1),
+            base_delay,
+        };
+        self
+    }
+    pub fn qos(mut self, qos: Qos) -> Self {
+        self.qos = qos;
+        self
+    }
+    /// Interval between the intermediate commands sent by `fade_color`. Defaults to 100ms.
+    pub fn fade_step(mut self, step: Duration) -> Self {
+        self.fade_step = step;
+        self
+    }
+    /// Builds an API whose commands go to `sink` instead of the Sengled broker, without logging
+    /// in or connecting. Endpoints that need the cloud (device listing, state subscriptions)
+    /// will fail.
+    pub fn build_with_sink<S: CommandSink + 'static>(self, sink: S) -> Result<SengledApi, Error> {
+        let mqtt_options = Arc::new(self.mqtt_options()?);
+        let client = mqtt::CreateOptionsBuilder::new()
+            .client_id(self.client_id.resolve(""))
+            .persistence(mqtt::PersistenceType::None)
+            .server_uri(self.region.mqtt_uri())
+            .create_client()?;
+        Ok(SengledApi {
+            mqtt_pending: Arc::new(futures::lock::Mutex::new(None)),
+            mqtt_options,
+            relogin: Arc::new(futures::lock::Mutex::new(())),
+            session: Arc::new(Mutex::new(SessionId::new("", self.session_lifetime))),
+            session_id: String::new(),
+            credentials: None,
+            login_retry: self.login_retry,
+            _connection: Arc::new(DisconnectOnDrop(client.clone())),
+            client,
+            sink: if self.dry_run {
+                Arc::new(DryRunSink)
+            } else {
+                Arc::new(sink)
+            },
+            subscribers: Arc::new(Mutex::new(vec![])),
+            connection_listeners: Arc::new(Mutex::new(vec![])),
+            region: self.region,
+            retry: self.retry,
+            qos: self.qos,
+            fade_step: self.fade_step,
+            min_command_interval: self.min_command_interval,
+            http_timeout: self.http_timeout,
+            last_sent: Arc::new(Mutex::new(HashMap::new())),
+            known_devices: Arc::new(Mutex::new(HashMap::new())),
+            sent_state: Arc::new(Mutex::new(HashMap::new())),
+            online: Arc::new(Mutex::new(HashMap::new())),
+            offline_policy: self.offline_policy,
+            clock: self.clock,
+            batch: Arc::new(Mutex::new(None)),
+            history: CommandHistory::new(self.command_history),
+            color_profile: self.color_profile,
+            http_client: self.http_client,
+            topic_template: self.topic_template,
+            status_topic_template: self.status_topic_template,
+            verify_colors: self.verify_colors,
+            lanes: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+    /// Spaces out commands to the same device by at least `interval`, delaying later ones as
+    /// needed. Off by default. Commands sent through `SengledApi::dispatch` are not delayed.
+    pub fn min_command_interval(mut self, interval: Duration) -> Self {
+        self.min_command_interval = Some(interval);
+        self
+    }
+    /// Keeps the last `size` commands sent, readable with `SengledApi::recent_commands`. Off
+    /// by default; a size of 0 turns it back off.
+    pub fn command_history(mut self, size: usize) -> Self {
+        self.command_history = size;
+        self
+    }
+    /// Authenticates to the MQTT broker with a username instead of the session cookie, for
+    /// brokers that reject cookie-only connections. Pair with `mqtt_password`.
+    pub fn mqtt_username<T: AsRef<str>>(mut self, username: T) -> Self {
+        self.mqtt_username = Some(username.as_ref().into());
+        self
+    }
+    pub fn mqtt_password<T: AsRef<str>>(mut self, password: T) -> Self {
+        self.mqtt_password = Some(password.as_ref().into());
+        self
+    }
+    /// Sends every HTTP request through `client`, so its connection pool, proxy and TLS
+    /// settings are shared with the rest of the application. The client type follows the
+    /// selected HTTP backend.
+    pub fn http_client(mut self, client: HttpClient) -> Self {
+        self.http_client = client;
+        self
+    }
+    /// The topic commands are published on, with `{mac}` replaced by the device's MAC address.
+    /// Defaults to `wifielement/{mac}/update`; hub-bridged devices may need a different prefix.
+    pub fn topic_template<T: AsRef<str>>(mut self, template: T) -> Self {
+        self.topic_template = template.as_ref().into();
+        self
+    }
+    /// The topic devices report their state on, filled in like `topic_template`. Defaults to
+    /// `wifielement/{mac}/status`; change it along with `topic_template`.
+    pub fn status_topic_template<T: AsRef<str>>(mut self, template: T) -> Self {
+        self.status_topic_template = template.as_ref().into();
+        self
+    }
+    /// How long a session is assumed to stay valid. Once it has likely expired, the next HTTP
+    /// request logs in again first and reconnects MQTT with the new session, provided the API
+    /// was created from a username and password. Defaults to 24 hours.
+    pub fn session_lifetime(mut self, lifetime: Duration) -> Self {
+        self.session_lifetime = lifetime;
+        self
+    }
+    /// How the MQTT client persists in-flight QoS 1 and 2 messages. Defaults to
+    /// `PersistenceType::None`; `PersistenceType::File` keeps them in files under the working
+    /// directory, and `PersistenceType::User` takes a custom store. Either way they only last
+    /// as long as the client: connections use a clean session, so nothing is resent after a
+    /// restart.
+    pub fn persistence(mut self, persistence: mqtt::PersistenceType) -> Self {
+        self.persistence = persistence;
+        self
+    }
+    /// Stamps command payloads with `clock` instead of the system time.
+    pub fn clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+    /// How to handle commands to devices the last device listing reported as offline. Defaults
+    /// to `OfflinePolicy::Warn`.
+    pub fn offline_policy(mut self, policy: OfflinePolicy) -> Self {
+        self.offline_policy = policy;
+        self
+    }
+    /// After each color command, waits up to `timeout` for the bulb to report the new color on
+    /// its status topic, failing with `Error::CommandRejected` if it doesn't. Meant for
+    /// diagnosing protocol changes; off by default. Commands buffered in a batch aren't checked.
+    pub fn verify_colors(mut self, timeout: Duration) -> Self {
+        self.verify_colors = Some(timeout);
+        self
+    }
+    /// Logs every command at info level instead of publishing it. Logging in and fetching
+    /// devices still hit the Sengled cloud.
+    pub fn dry_run(mut self) -> Self {
+        self.dry_run = true;
+        self
+    }
+    /// Corrects RGB colors through `profile` before they're sent. Off by default;
+    /// `ColorProfile::default()` is a reasonable starting point for Sengled bulbs.
+    pub fn color_profile(mut self, profile: ColorProfile) -> Self {
+        self.color_profile = Some(profile);
+        self
+    }
+    /// Limit on how long each HTTP request to the Sengled cloud may take before failing with
+    /// `Error::Timeout`. Defaults to 10 seconds.
+    pub fn http_timeout(mut self, timeout: Duration) -> Self {
+        self.http_timeout = timeout;
+        self
+    }
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip(self), fields(user = %self.user))
+    )]
+    pub async fn connect(self) -> Result<SengledApi, Error> {
+        let api = self.login().await?;
+        api.connect_mqtt().await?;
+        Ok(api)
+    }
+    /// Logs in over HTTP without connecting to MQTT. The returned API can list devices right
+    /// away; the MQTT connection is opened by `SengledApi::connect_mqtt` or, failing that, by
+    /// the first command or state subscription.
+    pub async fn login(self) -> Result<SengledApi, Error> {
+        let session_id = login(
+            &self.http_client,
+            &self.region,
+            &self.user,
+            &self.pass,
+            self.http_timeout,
+            self.login_retry,
+        )
+        .await?;
+        let credentials = Arc::new((self.user.clone(), self.pass.clone()));
+        let mut api = self.prepare(session_id)?;
+        api.credentials = Some(credentials);
+        Ok(api)
+    }
+    /// Connects to MQTT with a session ID from an earlier login, skipping authentication.
+    pub async fn connect_with_session_id(self, session_id: String) -> Result<SengledApi, Error> {
+        let api = self.prepare(session_id)?;
+        api.connect_mqtt().await?;
+        Ok(api)
+    }
+    fn mqtt_options(&self) -> Result<MqttOptions, Error> {
+        let ssl_options = match &self.ssl_options {
+            Some(options) => options.clone(),
+            None => {
+                let mut options = mqtt::SslOptionsBuilder::new();
+                if let Some(ca_file) = &self.ca_file {
+                    options.trust_store(ca_file)?;
+                }
+                if !self.verify_certificates {
+                    options.enable_server_cert_auth(false).verify(false);
+                }
+                options.finalize()
+            }
+        };
+        Ok(MqttOptions {
+            ssl_options,
+            keep_alive_interval: self.keep_alive_interval,
+            username: self.mqtt_username.clone(),
+            password: self.mqtt_password.clone(),
+            automatic_reconnect: self.automatic_reconnect,
+        })
+    }
+    /// Sets up the MQTT client for `session_id` without connecting it.
+    fn prepare(self, session_id: String) -> Result<SengledApi, Error> {
+        let mqtt_options = Arc::new(self.mqtt_options()?);
+        let mut client = mqtt::CreateOptionsBuilder::new()
+            .client_id(self.client_id.resolve(&session_id))
+            .persistence(self.persistence)
+            .server_uri(self.region.mqtt_uri())
+            .create_client()?;
+        let subscribers: Subscribers = Arc::new(Mutex::new(vec![]));
+        let dispatch = subscribers.clone();
+        client.set_message_callback(move |_, message| {
+            let message = match message {
+                Some(message) => message,
+                None => return,
+            };
+            let entries = match serde_json::from_slice(message.payload()) {
+                Ok(StatusMessage::Many(entries)) => entries,
+                Ok(StatusMessage::One(entry)) => vec![entry],
+                Err(_) => return,
+            };
+            let mut subscribers = dispatch.lock().unwrap();
+            subscribers.retain(|(_, sender)| !sender.is_closed());
+            for (topic, sender) in subscribers.iter() {
+                if topic == message.topic() {
+                    let _ = sender.unbounded_send(entries.clone());
+                }
+            }
+        });
+        let connection_listeners: ConnectionListeners = Arc::new(Mutex::new(vec![]));
+        let listeners = connection_listeners.clone();
+        let active = subscribers.clone();
+        client.set_connected_callback(move |client| {
+            log::info!("MQTT connected");
+            resubscribe(&active, |topics| {
+                drop(client.subscribe_many(topics, &vec![1; topics.len()]));
+            });
+            notify(&listeners, ConnectionEvent::Connected);
+        });
+        let listeners = connection_listeners.clone();
+        client.set_connection_lost_callback(move |_| {
+            log::warn!("MQTT connection lost");
+            notify(&listeners, ConnectionEvent::ConnectionLost);
+        });
+        Ok(SengledApi {
+            mqtt_pending: Arc::new(futures::lock::Mutex::new(Some(
+                mqtt_options.connect_options(&session_id),
+            ))),
+            mqtt_options,
+            relogin: Arc::new(futures::lock::Mutex::new(())),
+            session: Arc::new(Mutex::new(SessionId::new(
+                &session_id,
+                self.session_lifetime,
+            ))),
+            session_id,
+            credentials: None,
+            login_retry: self.login_retry,
+            _connection: Arc::new(DisconnectOnDrop(client.clone())),
+            sink: if self.dry_run {
+                Arc::new(DryRunSink)
+            } else {
+                Arc::new(client.clone())
+            },
+            client,
+            subscribers,
+            connection_listeners,
+            region: self.region,
+            retry: self.retry,
+            qos: self.qos,
+            fade_step: self.fade_step,
+            min_command_interval: self.min_command_interval,
+            http_timeout: self.http_timeout,
+            last_sent: Arc::new(Mutex::new(HashMap::new())),
+            known_devices: Arc::new(Mutex::new(HashMap::new())),
+            sent_state: Arc::new(Mutex::new(HashMap::new())),
+            online: Arc::new(Mutex::new(HashMap::new())),
+            offline_policy: self.offline_policy,
+            clock: self.clock,
+            batch: Arc::new(Mutex::new(None)),
+            history: CommandHistory::new(self.command_history),
+            color_profile: self.color_profile,
+            http_client: self.http_client,
+            topic_template: self.topic_template,
+            status_topic_template: self.status_topic_template,
+            verify_colors: self.verify_colors,
+            lanes: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+}
+
+impl SengledApi {
+    pub async fn new<T: AsRef<str>, U: AsRef<str>>(user: T, pass: U) -> Result<Self, Error> {
+        Self::new_with_region(user, pass, Region::default()).await
+    }
+    pub async fn new_with_region<T: AsRef<str>, U: AsRef<str>>(
+        user: T,
+        pass: U,
+        region: Region,
+    ) -> Result<Self, Error> {
+        Self::builder(user, pass).region(region).connect().await
+    }
+    pub async fn from_session_id(session_id: String, region: Region) -> Result<Self, Error> {
+        Self::builder("", "")
+            .region(region)
+            .connect_with_session_id(session_id)
+            .await
+    }
+    pub fn with_sink<S: CommandSink + 'static>(sink: S) -> Result<Self, Error> {
+        Self::builder("", "").build_with_sink(sink)
+    }
+    /// Logs in over HTTP only, deferring the MQTT connection. See `SengledApiBuilder::login`.
+    pub async fn login<T: AsRef<str>, U: AsRef<str>>(user: T, pass: U) -> Result<Self, Error> {
+        Self::builder(user, pass).login().await
+    }
+    pub fn builder<T: AsRef<str>, U: AsRef<str>>(user: T, pass: U) -> SengledApiBuilder {
+        SengledApiBuilder {
+            user: user.as_ref().into(),
+            pass: pass.as_ref().into(),
+            region: Region::default(),
+            keep_alive_interval: Duration::from_secs(30),
+            automatic_reconnect: Some((Duration::from_secs(1), Duration::from_secs(60))),
+            ssl_options: None,
+            ca_file: None,
+            verify_certificates: true,
+            client_id: ClientId::Suffix(Uuid::new_v4().to_simple().to_string()),
+            retry: RetryPolicy {
+                max_attempts: 3,
+                base_delay: Duration::from_millis(250),
+            },
+            login_retry: RetryPolicy {
+                max_attempts: 3,
+                base_delay: Duration::from_secs(1),
+            },
+            qos: Qos::default(),
+            fade_step: Duration::from_millis(100),
+            min_command_interval: None,
+            http_timeout: Duration::from_secs(10),
+            command_history: 0,
+            color_profile: None,
+            mqtt_username: None,
+            mqtt_password: None,
+            dry_run: false,
+            verify_colors: None,
+            http_client: HttpClient::new(),
+            topic_template: DEFAULT_TOPIC_TEMPLATE.into(),
+            status_topic_template: DEFAULT_STATUS_TOPIC_TEMPLATE.into(),
+            session_lifetime: Duration::from_secs(24 * 60 * 60),
+            offline_policy: OfflinePolicy::default(),
+            persistence: mqtt::PersistenceType::None,
+            clock: Arc::new(CurrentTime),
+        }
+    }
+    /// The session ID this API was created with. After a re-login the current one is in
+    /// `session`.
+    pub fn session_id(&self) -> &str {
+        &self.session_id
+    }
+    pub fn session(&self) -> SessionId {
+        self.session.lock().unwrap().clone()
+    }
+    /// The current session token, logging in again first if it has likely expired. Concurrent
+    /// callers wait for a single login rather than each logging in.
+    async fn session_token(&self) -> Result<String, Error> {
+        let (user, pass) = match &self.credentials {
+            Some(credentials) if self.session().is_likely_expired() => {
+                (&credentials.0, &credentials.1)
+            }
+            _ => return Ok(self.session().token),
+        };
+        let _relogin = self.relogin.lock().await;
+        let session = self.session();
+        if !session.is_likely_expired() {
+            return Ok(session.token);
+        }
+        log::debug!("session has likely expired, logging in again");
+        let token = login(
+            &self.http_client,
+            &self.region,
+            user,
+            pass,
+            self.http_timeout,
+            self.login_retry,
+        )
+        .await?;
+        *self.session.lock().unwrap() = SessionId::new(&token, session.lifetime);
+        self.renew_mqtt_session(&token).await?;
+        Ok(token)
+    }
+    /// Points the MQTT connection at a new session. A connection that hasn't been opened yet
+    /// will open with it; an open one is reconnected, as paho would otherwise keep presenting
+    /// the old cookie whenever it reconnects.
+    async fn renew_mqtt_session(&self, token: &str) -> Result<(), Error> {
+        let options = self.mqtt_options.connect_options(token);
+        let mut pending = self.mqtt_pending.lock().await;
+        if pending.is_some() {
+            *pending = Some(options);
+            return Ok(());
+        }
+        log::debug!("reconnecting to MQTT with the new session");
+        if self.client.is_connected() {
+            self.client.disconnect(None).await?;
+        }
+        self.client
+            .connect(options)
+            .await
+            .map_err(|e| connect_error(self.region.mqtt_uri(), e))?;
+        Ok(())
+    }
+    pub fn is_connected(&self) -> bool {
+        self.client.is_connected()
+    }
+    /// Opens the MQTT connection if it hasn't been opened yet, as for an API from
+    /// `SengledApi::login`. Does nothing once connected; later drops are handled by automatic
+    /// reconnection.
+    pub async fn connect_mqtt(&self) -> Result<(), Error> {
+        if self.mqtt_pending.lock().await.is_some() {
+            // A deferred connection opens with a fresh session, not one that has since expired.
+            self.session_token().await?;
+        }
+        let mut pending = self.mqtt_pending.lock().await;
+        if let Some(options) = pending.as_ref() {
+            log::debug!("connecting to MQTT broker at {}", self.region.mqtt_uri());
+            self.client
+                .connect(options.clone())
+                .await
+                .map_err(|e| connect_error(self.region.mqtt_uri(), e))?;
+            *pending = None;
+        }
+        Ok(())
+    }
+    /// The underlying paho client, for MQTT operations this crate doesn't wrap. Disconnecting
+    /// it or replacing its callbacks will break state subscriptions and connection events.
+    pub fn mqtt_client(&self) -> &mqtt::AsyncClient {
+        &self.client
+    }
+    /// The most recently sent commands, oldest first. Commands are kept once published, or for
+    /// `dispatch` once handed to the MQTT client, so failed sends don't appear. Always empty
+    /// unless enabled with `SengledApiBuilder::command_history`.
+    pub fn recent_commands(&self) -> Vec<SentCommand> {
+        self.history
+            .as_ref()
+            .map(|history| history.entries.lock().unwrap().iter().cloned().collect())
+            .unwrap_or_default()
+    }
+    fn record(&self, commands: &[Command]) {
+        if let Some(history) = &self.history {
+            history.record(commands);
+        }
+    }
+    pub fn connection_events(&self) -> impl Stream<Item = ConnectionEvent> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.connection_listeners.lock().unwrap().push(sender);
+        receiver
+    }
+    /// Gives up this handle's share of the MQTT connection, closing it and waiting for the
+    /// disconnect if this is the last clone. Other clones keep the connection open otherwise.
+    pub async fn disconnect(self) -> Result<(), Error> {
+        if Arc::strong_count(&self._connection) == 1 && self.client.is_connected() {
+            self.client.disconnect(None).await?;
+        }
+        Ok(())
+    }
+    async fn request<S: Serialize, T>(&self, uri: &str, data: Option<&S>) -> Result<T, Error>
+    where
+        for<'de> T: Deserialize<'de>,
+    {
+        recv_json(
+            &self.http_client,
+            uri,
+            data,
+            Some(format!("JSESSIONID={}", self.session_token().await?)),
+            self.http_timeout,
+        )
+        .await
+    }
+    async fn throttle(&self, dn: &Mac) {
+        let interval = match self.min_command_interval {
+            Some(interval) => interval,
+            None => return,
+        };
+        // Reserve the next free slot under the lock so concurrent commands keep their order.
+        let wait = {
+            let mut last_sent = self.last_sent.lock().unwrap();
+            let now = Instant::now();
+            let slot = last_sent
+                .get(dn)
+                .map(|last| *last + interval)
+                .filter(|slot| *slot > now)
+                .unwrap_or(now);
+            last_sent.insert(dn.clone(), slot);
+            slot - now
+        };
+        if wait > Duration::from_secs(0) {
+            smol::Timer::after(wait).await;
+        }
+    }
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            skip(self, command),
+            fields(mac = %command.dn, command = ?command.body.ty())
+        )
+    )]
+    async fn send_command(&self, command: &Command) -> Result<(), Error> {
+        if self.buffer(std::slice::from_ref(command)) {
+            return Ok(());
+        }
+        let _turn = self.queue(&command.dn).await;
+        self.check_online(&command.dn)?;
+        self.throttle(&command.dn).await;
+        self.publish(self.command_topic(command), self.command_json(command)?)
+            .await?;
+        self.record(std::slice::from_ref(command));
+        self.remember(std::slice::from_ref(command));
+        Ok(())
+    }
+    /// Publishes `command` without waiting for delivery or retrying, returning a handle that
+    /// can be awaited to confirm delivery separately. Unlike other commands this doesn't open
+    /// a deferred MQTT connection, so call `connect_mqtt` first after `login`. It also skips the
+    /// per-device ordering other commands get, so it can overtake a command to the same device
+    /// that is still being sent.
+    pub fn dispatch(&self, command: &Command) -> Result<Delivery, Error> {
+        self.check_online(&command.dn)?;
+        let delivery = Delivery(self.sink.publish(
+            self.command_topic(command),
+            self.command_json(command)?,
+            self.qos,
+        ));
+        self.record(std::slice::from_ref(command));
+        Ok(delivery)
+    }
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            skip(self, dn, commands),
+            fields(mac = %dn, commands = commands.len())
+        )
+    )]
+    async fn send_commands_to(&self, dn: &Mac, commands: &[Command]) -> Result<(), Error> {
+        if self.buffer(commands) {
+            return Ok(());
+        }
+        let _turn = self.queue(dn).await;
+        self.check_online(dn)?;
+        self.throttle(dn).await;
+        self.publish(
+            device_topic(&self.topic_template, dn),
+            serde_json::to_string(
+                &commands
+                    .iter()
+                    .map(|command| self.stamped(command))
+                    .collect::<Vec<_>>(),
+            )?,
+        )
+        .await?;
+        self.record(commands);
+        self.remember(commands);
+        Ok(())
+    }
+    async fn send_commands(&self, device: &Device, commands: &[Command]) -> Result<(), Error> {
+        self.send_commands_to(&device.uuid, commands).await
+    }
+    /// Waits until every send to `dn` started before this one has finished, so commands to one
+    /// device are published in the order they were issued even from different tasks. Sends to
+    /// other devices aren't held up, and `dispatch` bypasses the queue entirely. The next send
+    /// proceeds once the returned turn is dropped.
+    async fn queue(&self, dn: &Mac) -> Turn {
+        let (done, finished) = oneshot::channel::<()>();
+        let token = Arc::new(());
+        let previous = {
+            let mut lanes = self.lanes.lock().unwrap();
+            let previous = lanes.get(dn).map(|(_, lane)| lane.clone());
+            // This send's lane only clears after the ones before it, so a send dropped while
+            // waiting doesn't let the sends behind it overtake one still being published.
+            let before = previous.clone();
+            let lane = async move {
+                if let Some(before) = before {
+                    before.await;
+                }
+                // Cancellation just means this send finished.
+                let _ = finished.await;
+            }
+            .boxed()
+            .shared();
+            lanes.insert(dn.clone(), (token.clone(), lane));
+            previous
+        };
+        if let Some(previous) = previous {
+            previous.await;
+        }
+        Turn {
+            lanes: self.lanes.clone(),
+            dn: dn.clone(),
+            token,
+            _done: done,
+        }
+    }
+    /// Holds `commands` for the open batch, if there is one.
+    fn buffer(&self, commands: &[Command]) -> bool {
+        match &mut *self.batch.lock().unwrap() {
+            Some(batch) => {
+                batch.extend(commands.iter().cloned());
+                true
+            }
+            None => false,
+        }
+    }
+    /// Starts buffering commands instead of sending them, until `commit_batch`. Calls made in
+    /// the meantime return as soon as their commands are buffered. The batch is shared by all
+    /// clones of this API; `dispatch` is never buffered.
+    pub fn begin_batch(&self) {
+        let mut batch = self.batch.lock().unwrap();
+        if batch.is_none() {
+            *batch = Some(vec![]);
+        }
+    }
+    /// Sends everything buffered since `begin_batch`, one publish per device with that device's
+    /// commands in the order they were issued, all devices concurrently. Every device is
+    /// attempted, and each one's outcome is reported by MAC address. The result is empty if no
+    /// batch is open.
+    pub async fn commit_batch(&self) -> BatchResult<Mac> {
+        let commands = self.batch.lock().unwrap().take().unwrap_or_default();
+        let mut by_device: Vec<(Mac, Vec<Command>)> = vec![];
+        for command in commands {
+            match by_device.iter_mut().find(|(dn, _)| *dn == command.dn) {
+                Some((_, commands)) => commands.push(command),
+                None => by_device.push((command.dn.clone(), vec![command])),
+            }
+        }
+        let results = future::join_all(
+            by_device
+                .iter()
+                .map(|(dn, commands)| self.send_commands_to(dn, commands)),
+        )
+        .await;
+        BatchResult {
+            results: by_device
+                .into_iter()
+                .map(|(dn, _)| dn)
+                .zip(results)
+                .collect(),
+        }
+    }
+    /// Folds successfully published commands into the last known state of their devices.
+    fn remember(&self, commands: &[Command]) {
+        let mut sent_state = self.sent_state.lock().unwrap();
+        for command in commands {
+            sent_state
+                .entry(command.dn.clone())
+                .or_default()
+                .update(vec![command.body.status()]);
+        }
+    }
+    /// Sends `command` unless the device is already known to be in the state it sets,
+    /// returning whether anything was published. The known state is whatever this client
+    /// last sent, or failing that the state `device` was listed with.
+    async fn send_if_changed(&self, device: &Device, command: Command) -> Result<bool, Error> {
+        let known = self
+            .sent_state
+            .lock()
+            .unwrap()
+            .get(&device.uuid)
+            .cloned()
+            .unwrap_or_else(|| device.state());
+        let mut expected = known.clone();
+        expected.update(vec![command.body.status()]);
+        if expected == known {
+            return Ok(false);
+        }
+        self.send_command(&command).await?;
+        Ok(true)
+    }
+    async fn publish(&self, topic: String, payload: String) -> Result<(), Error> {
+        self.connect_mqtt().await?;
+        log::debug!("publishing to {}: {}", topic, payload);
+        let mut attempt = 1;
+        loop {
+            let result = self
+                .sink
+                .publish(topic.clone(), payload.clone(), self.qos)
+                .await;
+            match result {
+                Err(e) if attempt < self.retry.max_attempts && e.is_retryable() => {
+                    log::warn!(
+                        "publish to {} failed (attempt {}/{}): {}",
+                        topic,
+                        attempt,
+                        self.retry.max_attempts,
+                        e
+                    );
+                    smol::Timer::after(self.retry.delay(attempt)).await;
+                    attempt += 1;
+                }
+                Err(e) => {
+                    log::warn!("giving up publishing to {}: {}", topic, e);
+                    return Err(e);
+                }
+                Ok(()) => return Ok(()),
+            }
+        }
+    }
+    pub async fn subscribe_state(
+        &self,
+        device: &Device,
+    ) -> Result<impl Stream<Item = DeviceState>, Error> {
+        let topic = device_topic(&self.status_topic_template, &device.uuid);
+        let (sender, receiver) = mpsc::unbounded();
+        self.subscribers
+            .lock()
+            .unwrap()
+            .push((topic.clone(), sender));
+        self.connect_mqtt().await?;
+        self.client.subscribe(topic, 1).await?;
+        Ok(receiver.scan(DeviceState::default(), |state, entries| {
+            state.update(entries);
+            future::ready(Some(state.clone()))
+        }))
+    }
+    /// The device list exactly as Sengled returns it, including fields `Device` doesn't model.
+    pub async fn get_devices_raw(&self) -> Result<serde_json::Value, Error> {
+        self.request(
+            &format!("https://{}/life2/device/list.json", self.region.life_host()),
+            None::<&()>,
+        )
+        .await
+    }
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self)))]
+    pub async fn get_devices(&self) -> Result<Vec<Device>, Error> {
+        let resp: DevicesResponse = self
+            .request(
+                &format!("https://{}/life2/device/list.json", self.region.life_host()),
+                None::<&()>,
+            )
+            .await?;
+        let mut online = self.online.lock().unwrap();
+        online.clear();
+        online.extend(
+            resp.device_list
+                .iter()
+                .map(|device| (device.uuid.clone(), device.online)),
+        );
+        drop(online);
+        Ok(resp.device_list)
+    }
+    fn stamped<'a>(&'a self, command: &'a Command) -> Stamped<'a> {
+        Stamped {
+            command,
+            clock: &*self.clock,
+        }
+    }
+    /// The MQTT topic this API publishes `command` on, following its topic template.
+    pub fn command_topic(&self, command: &Command) -> String {
+        device_topic(&self.topic_template, &command.dn)
+    }
+    /// The JSON payload this API publishes `command` as, with `time` from its clock unless
+    /// fixed with `Command::at`.
+    pub fn command_json(&self, command: &Command) -> Result<String, Error> {
+        Ok(serde_json::to_string(&self.stamped(command))?)
+    }
+    fn check_online(&self, dn: &Mac) -> Result<(), Error> {
+        if self.offline_policy == OfflinePolicy::Ignore
+            || self.online.lock().unwrap().get(dn) != Some(&false)
+        {
+            return Ok(());
+        }
+        match self.offline_policy {
+            OfflinePolicy::Error => Err(Error::DeviceOffline(dn.to_string())),
+            _ => {
+                log::warn!(
+                    "sending a command to {}, which was offline when last listed",
+                    dn
+                );
+                Ok(())
+            }
+        }
+    }
+    /// Re-fetches the device list and diffs it against the list seen by the previous call,
+    /// so new or unplugged bulbs can be picked up without reconnecting. On the first call every
+    /// device counts as added. A device is changed when its name, room, online status, or
+    /// attributes differ.
+    pub async fn refresh_devices(&self) -> Result<DeviceChanges, Error> {
+        let devices = self.get_devices().await?;
+        let mut known = self.known_devices.lock().unwrap();
+        let mut changes = DeviceChanges::default();
+        let mut current = HashMap::with_capacity(devices.len());
+        for device in devices {
+            match known.remove(&device.uuid) {
+                None => changes.added.push(device.clone()),
+                Some(previous) => {
+                    if previous.name != device.name
+                        || previous.room != device.room
+                        || previous.online != device.online
+                        || previous.attributes != device.attributes
+                    {
+                        changes.changed.push(device.clone());
+                    }
+                }
+            }
+            current.insert(device.uuid.clone(), device);
+        }
+        changes.removed = known.drain().map(|(_, device)| device).collect();
+        *known = current;
+        Ok(changes)
+    }
+    /// Re-fetches the device list every `interval`, starting immediately, yielding each device
+    /// with its reported state. Reuses the current session.
+    pub fn poll_states(
+        &self,
+        interval: Duration,
+    ) -> impl Stream<Item = Result<Vec<(Device, DeviceState)>, Error>> + '_ {
+        stream::unfold(true, move |first| async move {
+            if !first {
+                smol::Timer::after(interval).await;
+            }
+            let states = self.get_devices().await.map(|devices| {
+                devices
+                    .into_iter()
+                    .map(|device| {
+                        let state = device.state();
+                        (device, state)
+                    })
+                    .collect()
+            });
+            Some((states, false))
+        })
+    }
+    /// Fetches the device list and keeps only the devices matching `predicate`. Sengled's
+    /// `list.json` has no filter parameters, so the full list is still downloaded.
+    pub async fn get_devices_filtered<F: FnMut(&Device) -> bool>(
+        &self,
+        mut predicate: F,
+    ) -> Result<Vec<Device>, Error> {
+        let mut devices = self.get_devices().await?;
+        devices.retain(|device| predicate(device));
+        Ok(devices)
+    }
+    pub async fn get_online_devices(&self) -> Result<Vec<Device>, Error> {
+        self.get_devices_filtered(|device| device.online).await
+    }
+    /// Groups the account's devices by the room they are assigned to in the Sengled app, in
+    /// order of first appearance. Devices without a room are left out.
+    pub async fn get_rooms(&self) -> Result<Vec<Room>, Error> {
+        let mut rooms: Vec<Room> = vec![];
+        for device in self.get_devices().await? {
+            let name = match &device.room {
+                Some(name) => name.clone(),
+                None => continue,
+            };
+            match rooms.iter_mut().find(|room| room.name == name) {
+                Some(room) => room.devices.push(device),
+                None => rooms.push(Room {
+                    name,
+                    devices: vec![device],
+                }),
+            }
+        }
+        Ok(rooms)
+    }
+    /// Lists the distinct `typeCode` and `productCode` values among the account's devices,
+    /// sorted and without duplicates.
+    pub async fn product_types(&self) -> Result<Vec<String>, Error> {
+        let mut types = BTreeSet::new();
+        for device in self.get_devices().await? {
+            types.extend(device.type_code);
+            types.extend(device.product_code);
+        }
+        Ok(types.into_iter().collect())
+    }
+    /// Looks up the capabilities of the device with the given MAC address, failing with
+    /// `Error::DeviceNotFound` if it isn't on the account.
+    pub async fn device_capabilities<M>(&self, mac: M) -> Result<Capabilities, Error>
+    where
+        M: TryInto<Mac>,
+        Error: From<M::Error>,
+    {
+        let mac = mac.try_into()?;
+        self.get_devices()
+            .await?
+            .into_iter()
+            .find(|device| device.uuid == mac)
+            .map(|device| device.capabilities())
+            .ok_or_else(|| Error::DeviceNotFound(mac.to_string()))
+    }
+    /// Fetches a fresh device listing and returns `device`'s energy figures, or `None` if it
+    /// doesn't report any. Fails with `Error::DeviceNotFound` if it's no longer on the account.
+    pub async fn energy_usage(&self, device: &Device) -> Result<Option<EnergyStats>, Error> {
+        self.get_devices()
+            .await?
+            .into_iter()
+            .find(|item| item == device)
+            .map(|item| item.energy_stats())
+            .ok_or_else(|| Error::DeviceNotFound(device.uuid.to_string()))
+    }
+    pub async fn get_device_by_name(&self, name: &str) -> Result<Option<Device>, Error> {
+        Ok(self
+            .get_devices()
+            .await?
+            .into_iter()
+            .find(|device| device.name == name))
+    }
+    pub async fn set_switch(&self, device: &Device, on: bool) -> Result<(), Error> {
+        self.send_command(&Command::switch(device, on)).await
+    }
+    pub async fn turn_on(&self, device: &Device) -> Result<(), Error> {
+        self.set_switch(device, true).await
+    }
+    pub async fn turn_off(&self, device: &Device) -> Result<(), Error> {
+        self.set_switch(device, false).await
+    }
+    /// Turns on the device with the given MAC address without needing a `Device` from a
+    /// listing. Like the other `*_mac` methods it takes a `Mac` or a string to parse as one,
+    /// failing with `Error::InvalidMac` if it isn't a MAC address.
+    pub async fn turn_on_mac<M>(&self, mac: M) -> Result<(), Error>
+    where
+        M: TryInto<Mac>,
+        Error: From<M::Error>,
+    {
+        self.send_command(&Command::for_mac(
+            mac.try_into()?,
+            CommandBody::Switch(true),
+        ))
+        .await
+    }
+    pub async fn turn_off_mac<M>(&self, mac: M) -> Result<(), Error>
+    where
+        M: TryInto<Mac>,
+        Error: From<M::Error>,
+    {
+        self.send_command(&Command::for_mac(
+            mac.try_into()?,
+            CommandBody::Switch(false),
+        ))
+        .await
+    }
+    pub async fn set_brightness_mac<M>(&self, mac: M, brightness: Brightness) -> Result<(), Error>
+    where
+        M: TryInto<Mac>,
+        Error: From<M::Error>,
+    {
+        self.send_command(&Command::for_mac(
+            mac.try_into()?,
+            CommandBody::Brightness(brightness.percent()),
+        ))
+        .await
+    }
+    /// Sets the color of the device with the given MAC address. Without a device listing the
+    /// bulb's color support can't be checked, so RGB colors are sent as-is.
+    pub async fn set_color_mac<M>(&self, mac: M, color: Color) -> Result<(), Error>
+    where
+        M: TryInto<Mac>,
+        Error: From<M::Error>,
+    {
+        self.send_command(&Command::for_mac(
+            mac.try_into()?,
+            CommandBody::color(self.correct(color)),
+        ))
+        .await
+    }
+    /// Flips the switch state, returning the new state. The current state is read from a fresh
+    /// device listing; if it can't be determined the device is turned on.
+    pub async fn toggle(&self, device: &Device) -> Result<bool, Error> {
+        let on = self
+            .get_devices()
+            .await?
+            .into_iter()
+            .find(|item| item == device)
+            .and_then(|item| item.state().on)
+            .unwrap_or(false);
+        self.set_switch(device, !on).await?;
+        Ok(!on)
+    }
+    pub async fn set_brightness(
+        &self,
+        device: &Device,
+        brightness: Brightness,
+    ) -> Result<(), Error> {
+        self.set_brightness_percent(device, brightness.percent())
+            .await
+    }
+    pub async fn set_brightness_percent(&self, device: &Device, percent: u8) -> Result<(), Error> {
+        self.send_command(&Command::brightness(device, percent))
+            .await
+    }
+    fn correct(&self, color: Color) -> Color {
+        match self.color_profile {
+            Some(profile) => profile.apply(color),
+            None => color,
+        }
+    }
+    fn check_color(device: &Device, color: Color) -> Result<(), Error> {
+        match color {
+            Color::Rgb { .. } if !device.supports_color() => Err(Error::UnsupportedCapability {
+                device: device.name.clone(),
+                capability: "color",
+            }),
+            _ => Ok(()),
+        }
+    }
+    /// Sets the color, failing with `Error::UnsupportedCapability` if an RGB color is sent to a
+    /// white-only bulb instead of publishing a command it would ignore.
+    pub async fn set_color(&self, device: &Device, color: Color) -> Result<(), Error> {
+        Self::check_color(device, color)?;
+        self.try_set_color(device, color).await
+    }
+    /// Like [`set_color`](Self::set_color), but publishes without checking whether the device
+    /// supports color.
+    pub async fn try_set_color(&self, device: &Device, color: Color) -> Result<(), Error> {
+        self.send_color(device, self.correct(color)).await
+    }
+    /// Sends `color` as given, already corrected for the color profile.
+    async fn send_color(&self, device: &Device, color: Color) -> Result<(), Error> {
+        let command = Command::color(device, color);
+        match self.verify_colors {
+            Some(timeout) if self.batch.lock().unwrap().is_none() => {
+                self.send_verified(&command, timeout).await
+            }
+            _ => self.send_command(&command).await,
+        }
+    }
+    /// Sends `command` and waits for the device to report the value it sets on its status
+    /// topic. The subscription is made first so a quick confirmation isn't missed.
+    async fn send_verified(&self, command: &Command, timeout: Duration) -> Result<(), Error> {
+        let expected = command.body.status();
+        let topic = device_topic(&self.status_topic_template, &command.dn);
+        let (sender, receiver) = mpsc::unbounded();
+        self.subscribers
+            .lock()
+            .unwrap()
+            .push((topic.clone(), sender));
+        let result = async {
+            let mut receiver = receiver;
+            self.connect_mqtt().await?;
+            self.client.subscribe(topic.as_str(), 1).await?;
+            self.send_command(command).await?;
+            let reported = Mutex::new(None);
+            let confirmed = smol::future::or(
+                async {
+                    while let Some(entries) = receiver.next().await {
+                        for entry in entries.into_iter().filter(|entry| entry.ty == expected.ty) {
+                            if entry.value == expected.value {
+                                return true;
+                            }
+                            *reported.lock().unwrap() = Some(entry.value);
+                        }
+                    }
+                    false
+                },
+                async {
+                    smol::Timer::after(timeout).await;
+                    false
+                },
+            )
+            .await;
+            if confirmed {
+                Ok(())
+            } else {
+                Err(Error::CommandRejected {
+                    device: command.dn.to_string(),
+                    expected: expected.value,
+                    reported: reported.into_inner().unwrap(),
+                })
+            }
+        }
+        .await;
+        // Leave the topic subscribed only if a `subscribe_state` stream still wants it.
+        let unused = {
+            let mut subscribers = self.subscribers.lock().unwrap();
+            subscribers.retain(|(_, sender)| !sender.is_closed());
+            !subscribers
+                .iter()
+                .any(|(subscribed, _)| *subscribed == topic)
+        };
+        if unused {
+            if let Err(e) = self.client.unsubscribe(topic.as_str()).await {
+                log::debug!("failed to unsubscribe from {}: {}", topic, e);
+            }
+        }
+        result
+    }
+    /// Sets color and brightness in a single publish. Brightness is applied first so the bulb
+    /// never shows the new color at its previous brightness.
+    pub async fn set_color_and_brightness(
+        &self,
+        device: &Device,
+        color: Color,
+        brightness: Brightness,
+    ) -> Result<(), Error> {
+        Self::check_color(device, color)?;
+        self.send_commands(
+            device,
+            &[
+                Command::brightness(device, brightness.percent()),
+                Command::color(device, self.correct(color)),
+            ],
+        )
+        .await
+    }
+    /// Sets white color temperature on Sengled's native scale, where 0 is the warmest (2000K)
+    /// and 100 the coolest (6500K) the bulb supports. Values above 100 are clamped.
+    pub async fn set_color_temp_percent(&self, device: &Device, percent: u8) -> Result<(), Error> {
+        self.send_command(&Command::color_temperature(device, percent))
+            .await
+    }
+    /// Fades from the device's last known color to `to` over `duration`, sending one command
+    /// per fade step. The last known color is the last one this client sent, or failing that
+    /// the one `device` was listed with. Dropping the future stops the fade where it is. If the
+    /// current color is unknown the color is set immediately.
+    pub async fn fade_color(
+        &self,
+        device: &Device,
+        to: Color,
+        duration: Duration,
+    ) -> Result<(), Error> {
+        let progress = self.fade_color_progress(device, to, duration);
+        futures::pin_mut!(progress);
+        while let Some(result) = progress.next().await {
+            result?;
+        }
+        Ok(())
+    }
+    /// Like [`fade_color`](Self::fade_color), but yields the fraction completed and the color
+    /// just sent, after color profile correction, after each step. Dropping the stream stops
+    /// the fade; it ends after the final step or the first failed command.
+    pub fn fade_color_progress<'a>(
+        &'a self,
+        device: &'a Device,
+        to: Color,
+        duration: Duration,
+    ) -> impl Stream<Item = Result<(f32, Color), Error>> + 'a {
+        // The last sent color has been through the color profile already, so the fade runs
+        // between corrected colors and each step is sent without correcting it again.
+        let to = self.correct(to);
+        let from = self
+            .sent_state
+            .lock()
+            .unwrap()
+            .get(&device.uuid)
+            .and_then(|state| state.color)
+            .or_else(|| device.state().color);
+        let steps = match from {
+            Some(_) => (duration.as_millis() / self.fade_step.as_millis().max(1))
+                .clamp(1, u32::MAX as u128) as u32,
+            None => 1,
+        };
+        stream::unfold(Some(1), move |step| async move {
+            let step = step?;
+            if step > 1 {
+                smol::Timer::after(self.fade_step).await;
+            }
+            let fraction = step as f64 / steps as f64;
+            // The last step sends `to` as given, so a fade between RGB and white still ends on
+            // a color temperature.
+            let color = match from {
+                Some(from) if step < steps => from.interpolate(to, fraction),
+                _ => to,
+            };
+            let sent = match Self::check_color(device, color) {
+                Ok(()) => self.send_color(device, color).await,
+                Err(e) => Err(e),
+            };
+            match sent {
+                Ok(()) => Some((
+                    Ok((fraction as f32, color)),
+                    Some(step + 1).filter(|next| *next <= steps),
+                )),
+                Err(e) => Some((Err(e), None)),
+            }
+        })
+    }
+    /// Like [`set_color`](Self::set_color), but skips the publish when the device is already
+    /// known to have `color`. Returns whether a command was sent.
+    pub async fn set_color_if_changed(&self, device: &Device, color: Color) -> Result<bool, Error> {
+        Self::check_color(device, color)?;
+        self.send_if_changed(device, Command::color(device, self.correct(color)))
+            .await
+    }
+    /// Like [`set_brightness`](Self::set_brightness), but skips the publish when the
+    /// brightness is unchanged. Returns whether a command was sent.
+    pub async fn set_brightness_if_changed(
+        &self,
+        device: &Device,
+        brightness: Brightness,
+    ) -> Result<bool, Error> {
+        self.send_if_changed(device, Command::brightness(device, brightness.percent()))
+            .await
+    }
+    /// Switches the device on or off unless it's already known to be in that state. Returns
+    /// whether a command was sent.
+    pub async fn switch_if_changed(&self, device: &Device, on: bool) -> Result<bool, Error> {
+        self.send_if_changed(device, Command::switch(device, on))
+            .await
+    }
+    /// Sets an RGB color from HSV, with `hue` in degrees (0–360) and `saturation` and `value`
+    /// as percentages (0–100). Out-of-range components fail with `Error::InvalidHsv` rather
+    /// than being wrapped or clamped.
+    pub async fn set_color_hsv(
+        &self,
+        device: &Device,
+        hue: u16,
+        saturation: u8,
+        value: u8,
+    ) -> Result<(), Error> {
+        if hue > 360 || saturation > 100 || value > 100 {
+            return Err(Error::InvalidHsv {
+                hue,
+                saturation,
+                value,
+            });
+        }
+        self.set_color(
+            device,
+            Color::from_hsv(hue as f32, saturation as f32 / 100., value as f32 / 100.),
+        )
+        .await
+    }
+    /// Sweeps `device` around the full hue wheel once every `period`, at full saturation and
+    /// brightness, until the future is dropped or a command fails. A new color is sent every
+    /// fade step, subject to any configured minimum command interval.
+    pub async fn rainbow(&self, device: &Device, period: Duration) -> Result<(), Error> {
+        let period = period.as_secs_f64().max(0.001);
+        let start = Instant::now();
+        loop {
+            let hue = (start.elapsed().as_secs_f64() % period) / period * 360.;
+            self.set_color(device, Color::from_hsv(hue as f32, 1., 1.))
+                .await?;
+            smol::Timer::after(self.fade_step).await;
+        }
+    }
+    /// Cycles `device` through `colors`, holding each for `interval`, until the future is
+    /// dropped or a command fails. Returns immediately if `colors` is empty.
+    pub async fn pulse(
+        &self,
+        device: &Device,
+        colors: &[Color],
+        interval: Duration,
+    ) -> Result<(), Error> {
+        if colors.is_empty() {
+            return Ok(());
+        }
+        for color in colors.iter().cycle() {
+            self.set_color(device, *color).await?;
+            smol::Timer::after(interval).await;
+        }
+        Ok(())
+    }
+    /// Like [`pulse`](Self::pulse), but stops gracefully once `stop` completes, leaving the
+    /// device at whichever color was last sent.
+    pub async fn pulse_until<F: Future<Output = ()>>(
+        &self,
+        device: &Device,
+        colors: &[Color],
+        interval: Duration,
+        stop: F,
+    ) -> Result<(), Error> {
+        smol::future::or(self.pulse(device, colors, interval), async move {
+            stop.await;
+            Ok(())
+        })
+        .await
+    }
+    /// Sets a white color temperature in Kelvin. Values outside the 2000K–6500K range the bulbs
+    /// support are clamped to the nearest bound.
+    pub async fn set_color_temperature(&self, device: &Device, kelvin: u16) -> Result<(), Error> {
+        self.set_color(
+            device,
+            Color::White {
+                temperature: kelvin.into(),
+            },
+        )
+        .await
+    }
+    /// Applies every member of `scene` concurrently, one publish per device. Members with
+    /// nothing to set are skipped and left out of the result.
+    pub async fn apply_scene(&self, scene: &Scene) -> BatchResult {
+        let results = future::join_all(
+            scene
+                .members
+                .iter()
+                .map(|(device, state)| {
+                    let state = DeviceState {
+                        color: state.color.map(|color| self.correct(color)),
+                        ..state.clone()
+                    };
+                    (device, state.commands(device))
+                })
+                .filter(|(_, commands)| !commands.is_empty())
+                .map(|(device, commands)| async move {
+                    (device.clone(), self.send_commands(device, &commands).await)
+                }),
+        )
+        .await;
+        BatchResult { results }
+    }
+    async fn batch<'a, F, Fut>(devices: impl IntoIterator<Item = &'a Device>, f: F) -> BatchResult
+    where
+        F: Fn(&'a Device) -> Fut,
+        Fut: Future<Output = Result<(), Error>>,
+    {
+        let results = future::join_all(devices.into_iter().map(|device| {
+            let result = f(device);
+            async move { (device.clone(), result.await) }
+        }))
+        .await;
+        BatchResult { results }
+    }
+    pub async fn turn_on_many(&self, devices: &[&Device]) -> BatchResult {
+        Self::batch(devices.iter().copied(), |device| self.turn_on(device)).await
+    }
+    pub async fn turn_off_many(&self, devices: &[&Device]) -> BatchResult {
+        Self::batch(devices.iter().copied(), |device| self.turn_off(device)).await
+    }
+    pub async fn set_brightness_many(
+        &self,
+        devices: &[&Device],
+        brightness: Brightness,
+    ) -> BatchResult {
+        Self::batch(devices.iter().copied(), |device| {
+            self.set_brightness(device, brightness)
+        })
+        .await
+    }
+    /// Sets every tunable-white bulb in `devices` to `kelvin` concurrently, for driving a
+    /// circadian lighting curve. Bulbs without tunable white are skipped and reported with
+    /// `Error::UnsupportedCapability`.
+    pub async fn set_circadian(&self, devices: &[&Device], kelvin: u16) -> BatchResult {
+        Self::batch(devices.iter().copied(), |device| async move {
+            if !device.capabilities().tunable_white {
+                return Err(Error::UnsupportedCapability {
+                    device: device.name.clone(),
+                    capability: "tunable white",
+                });
+            }
+            self.set_color_temperature(device, kelvin).await
+        })
+        .await
+    }
+    pub async fn set_color_many(&self, devices: &[&Device], color: Color) -> BatchResult {
+        Self::batch(devices.iter().copied(), |device| {
+            self.set_color(device, color)
+        })
+        .await
+    }
+    /// Like [`set_color_many`](Self::set_color_many), but sends to one device at a time in
+    /// slice order, waiting `stagger` between devices for a wave-like effect.
+    pub async fn set_color_many_staggered(
+        &self,
+        devices: &[&Device],
+        color: Color,
+        stagger: Duration,
+    ) -> BatchResult {
+        let mut results = Vec::with_capacity(devices.len());
+        for (i, device) in devices.iter().enumerate() {
+            if i > 0 {
+                smol::Timer::after(stagger).await;
+            }
+            results.push(((*device).clone(), self.set_color(device, color).await));
+        }
+        BatchResult { results }
+    }
+    /// Turns off every device on the account concurrently. Only a failure to fetch the device
+    /// list is returned as an error; each device's outcome is reported in the `BatchResult` so
+    /// one unreachable bulb doesn't abort the rest.
+    pub async fn turn_off_all(&self) -> Result<BatchResult, Error> {
+        let devices = self.get_devices().await?;
+        Ok(Self::batch(&devices, |device| self.turn_off(device)).await)
+    }
+    /// Like [`turn_off_all`](Self::turn_off_all), but sets the brightness of every device.
+    pub async fn set_brightness_all(&self, brightness: Brightness) -> Result<BatchResult, Error> {
+        let devices = self.get_devices().await?;
+        Ok(Self::batch(&devices, |device| self.set_brightness(device, brightness)).await)
+    }
+    /// Like [`turn_off_all`](Self::turn_off_all), but sets the color of every device.
+    pub async fn set_color_all(&self, color: Color) -> Result<BatchResult, Error> {
+        let devices = self.get_devices().await?;
+        Ok(Self::batch(&devices, |device| self.set_color(device, color)).await)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: &str = "B0:CE:18:00:00:01";
+
+    struct FixedClock(u64);
+
+    impl Clock for FixedClock {
+        fn now_millis(&self) -> u64 {
+            self.0
+        }
+    }
+
+    fn device() -> Device {
+        serde_json::from_str(&format!(
+            r#"{{"deviceUuid":"{}","attributeList":[{{"name":"name","value":"Lamp"}},{{"name":"color","value":"0:0:0"}}]}}"#,
+            MAC
+        ))
+        .unwrap()
+    }
+
+    fn mock_api() -> (SengledApi, MockSink) {
+        let sink = MockSink::new();
+        let api = SengledApi::builder("", "")
+            .clock(FixedClock(1_600_000_000_000))
+            .build_with_sink(sink.clone())
+            .unwrap();
+        (api, sink)
+    }
+
+    /// Checks that `sink` saw exactly one command, to `MAC`'s update topic, with this type and
+    /// value, byte for byte.
+    fn assert_published(sink: &MockSink, ty: &str, value: &str) {
+        assert_eq!(
+            sink.published(),
+            vec![(
+                format!("wifielement/{}/update", MAC),
+                format!(
+                    r#"{{"type":"{}","dn":"{}","value":"{}","time":1600000000000}}"#,
+                    ty, MAC, value
+                )
+            )]
+        );
+    }
+
+    #[test]
+    fn injected_clock_stamps_payload() {
+        let (api, sink) = mock_api();
+        let command = Command::switch(&device(), false);
+        smol::block_on(api.send_command(&command)).unwrap();
+        let expected = format!(
+            r#"{{"type":"switch","dn":"{}","value":"0","time":1600000000000}}"#,
+            MAC
+        );
+        assert_eq!(sink.published()[0].1.as_bytes(), expected.as_bytes());
+        assert_eq!(
+            command.at(1_600_000_000_000).to_json().unwrap().as_bytes(),
+            expected.as_bytes()
+        );
+    }
+
+    #[test]
+    fn mac_display_zero_pads() {
+        let mac = Mac::from([0x0A, 0xB3, 0x00, 0x01, 0xFE, 0x02]);
+        assert_eq!(mac.to_string(), "0A:B3:00:01:FE:02");
+        assert_eq!("0A:B3:00:01:FE:02".parse::<Mac>().unwrap(), mac);
+        let device: Device = serde_json::from_str(
+            r#"{"deviceUuid":"0A:B3:00:01:FE:02","attributeList":[{"name":"name","value":"Lamp"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(device.uuid(), mac);
+        assert_eq!(device.mac_string(), "0A:B3:00:01:FE:02");
+        assert_eq!(Mac::try_from("0a:b3:00:01:fe:02").unwrap(), mac);
+        assert!(matches!(Mac::try_from("0A:B3"), Err(Error::InvalidMac(_))));
+    }
+
+    #[test]
+    fn temperature_boundaries() {
+        assert_eq!(temperature_to_percent(2000), 0);
+        assert_eq!(temperature_to_percent(6500), 100);
+        assert_eq!(temperature_to_percent(1000), 0);
+        assert_eq!(temperature_to_percent(0), 0);
+        assert_eq!(temperature_to_percent(9000), 100);
+        let (api, sink) = mock_api();
+        smol::block_on(api.set_color_temperature(&device(), 1500)).unwrap();
+        smol::block_on(api.set_color_temperature(&device(), 10000)).unwrap();
+        let values: Vec<_> = sink
+            .published()
+            .into_iter()
+            .map(|(_, payload)| payload)
+            .collect();
+        assert_eq!(
+            values,
+            vec![
+                format!(
+                    r#"{{"type":"colorTemperature","dn":"{}","value":"0","time":1600000000000}}"#,
+                    MAC
+                ),
+                format!(
+                    r#"{{"type":"colorTemperature","dn":"{}","value":"100","time":1600000000000}}"#,
+                    MAC
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn set_color_publishes_rgb() {
+        let (api, sink) = mock_api();
+        let color = Color::Rgb {
+            red: 255,
+            green: 0,
+            blue: 0,
+        };
+        smol::block_on(api.set_color(&device(), color)).unwrap();
+        assert_published(&sink, "color", "255:0:0");
+    }
+
+    #[test]
+    fn turn_on_publishes_switch() {
+        let (api, sink) = mock_api();
+        smol::block_on(api.turn_on(&device())).unwrap();
+        assert_published(&sink, "switch", "1");
+    }
+
+    #[test]
+    fn set_brightness_publishes_percent() {
+        let (api, sink) = mock_api();
+        let brightness = Brightness::from_percent(42).unwrap();
+        smol::block_on(api.set_brightness(&device(), brightness)).unwrap();
+        assert_published(&sink, "brightness", "42");
+    }
+
+    #[test]
+    fn from_hex_inputs() {
+        for hex in &["#FF8800", "ff8800"] {
+            assert!(matches!(
+                Color::from_hex(hex),
+                Ok(Color::Rgb {
+                    red: 0xFF,
+                    green: 0x88,
+                    blue: 0x00
+                })
+            ));
+        }
+        assert_eq!(
+            Color::from_hex("#GGGGGG").unwrap_err(),
+            ParseColorError::InvalidDigit
+        );
+        assert_eq!(
+            Color::from_hex("#FF880").unwrap_err(),
+            ParseColorError::InvalidLength(5)
+        );
+        assert_eq!(
+            Color::from_hex("FF88000").unwrap_err(),
+            ParseColorError::InvalidLength(7)
+        );
+        assert_eq!(
+            Color::from_hex("+F8800").unwrap_err(),
+            ParseColorError::InvalidDigit
+        );
+    }
+
+    fn login(body: &str) -> Result<String, Error> {
+        serde_json::from_str::<LoginResponse>(body)
+            .unwrap()
+            .session_id()
+    }
+
+    #[test]
+    fn login_response_success() {
+        let body = r#"{"ret":0,"msg":"success","jsessionId":"abc123","customerId":42,"nickName":"x","jbalancerId":"1"}"#;
+        assert_eq!(login(body).unwrap(), "abc123");
+        assert_eq!(login(r#"{"jsessionId":"abc123"}"#).unwrap(), "abc123");
+    }
+
+    #[test]
+    fn login_response_failure() {
+        match login(r#"{"ret":100,"msg":"wrong password","jsessionId":null}"#) {
+            Err(Error::AuthenticationFailure { code, message }) => {
+                assert_eq!(code, 100);
+                assert_eq!(message, "wrong password");
+            }
+            other => panic!("expected authentication failure, got {:?}", other),
+        }
+        assert!(matches!(
+            login(r#"{"ret":1,"msg":"locked","jsessionId":"abc123"}"#),
+            Err(Error::AuthenticationFailure { code: 1, .. })
+        ));
+        assert!(matches!(
+            login(r#"{"msg":"error"}"#),
+            Err(Error::AuthenticationFailure { code: -1, .. })
+        ));
+    }
+
+    #[test]
+    fn u8_percent_conversions() {
+        assert_eq!(u8_to_percent(0), 0);
+        assert_eq!(u8_to_percent(1), 0);
+        assert_eq!(u8_to_percent(128), 50);
+        assert_eq!(u8_to_percent(255), 100);
+        for percent in 0..=100 {
+            assert_eq!(u8_to_percent(percent_to_u8(percent)), percent);
+        }
+        assert_eq!(percent_to_u8(101), 255);
+        assert_eq!(percent_to_u8(255), 255);
+    }
+
+    #[test]
+    fn reconnect_restores_live_subscriptions() {
+        let subscribers: Subscribers = Arc::new(Mutex::new(vec![]));
+        let (live, receiver) = mpsc::unbounded();
+        let (gone, _) = mpsc::unbounded();
+        subscribers.lock().unwrap().extend(vec![
+            ("wifielement/A/status".to_string(), live.clone()),
+            ("wifielement/A/status".to_string(), live),
+            ("wifielement/B/status".to_string(), gone),
+        ]);
+        // Each connect, first or after a drop, must restore the same live topics.
+        for _ in 0..2 {
+            let mut restored = vec![];
+            resubscribe(&subscribers, |topics| restored.extend_from_slice(topics));
+            assert_eq!(restored, vec!["wifielement/A/status".to_string()]);
+        }
+        assert_eq!(subscribers.lock().unwrap().len(), 2);
+        drop(receiver);
+        let mut restored = vec![];
+        resubscribe(&subscribers, |topics| restored.extend_from_slice(topics));
+        assert!(restored.is_empty());
+    }
+
+    #[test]
+    fn client_id_suffixes() {
+        let id = |builder: SengledApiBuilder| builder.client_id.resolve("session");
+        let default = id(SengledApi::builder("", ""));
+        let suffixed = id(SengledApi::builder("", "").client_id_suffix("kitchen"));
+        assert!(default.starts_with("session@lifeApp-"));
+        assert!(default.len() > "session@lifeApp-".len());
+        assert_ne!(default, suffixed);
+        assert_eq!(suffixed, "session@lifeApp-kitchen");
+        // Two processes on one account each get their own random suffix.
+        assert_ne!(default, id(SengledApi::builder("", "")));
+    }
+
+    #[test]
+    fn finished_sends_release_their_lane() {
+        let (api, sink) = mock_api();
+        smol::block_on(future::join(
+            api.turn_on(&device()),
+            api.turn_off(&device()),
+        ))
+        .0
+        .unwrap();
+        assert_eq!(sink.published().len(), 2);
+        assert!(sink.published()[0].1.contains(r#""value":"1""#));
+        assert!(api.lanes.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn disconnect_leaves_shared_connection_to_other_clones() {
+        let (api, _) = mock_api();
+        let clone = api.clone();
+        smol::block_on(clone.disconnect()).unwrap();
+        assert_eq!(Arc::strong_count(&api._connection), 1);
+        smol::block_on(api.disconnect()).unwrap();
+    }
+
+    #[test]
+    fn fade_starts_from_last_sent_color() {
+        let sink = MockSink::new();
+        let api = SengledApi::builder("", "")
+            .fade_step(Duration::from_millis(1))
+            .build_with_sink(sink.clone())
+            .unwrap();
+        let device = device();
+        let red = Color::Rgb {
+            red: 200,
+            green: 0,
+            blue: 0,
+        };
+        let blue = Color::Rgb {
+            red: 0,
+            green: 0,
+            blue: 200,
+        };
+        smol::block_on(async {
+            api.set_color(&device, red).await.unwrap();
+            let steps: Vec<_> = api
+                .fade_color_progress(&device, blue, Duration::from_millis(4))
+                .collect()
+                .await;
+            let (_, first) = steps[0].as_ref().unwrap();
+            // The listing reported black; a fade from there would have no red in it.
+            assert!(matches!(first, Color::Rgb { red, .. } if *red > 0));
+        });
+        assert!(sink.published().len() > 1);
+    }
+
+    #[test]
+    fn fade_to_white_ends_on_temperature() {
+        let sink = MockSink::new();
+        let api = SengledApi::builder("", "")
+            .clock(FixedClock(1_600_000_000_000))
+            .fade_step(Duration::from_millis(1))
+            .build_with_sink(sink.clone())
+            .unwrap();
+        let device = device();
+        smol::block_on(api.fade_color(&device, Color::WARM_WHITE, Duration::from_millis(4)))
+            .unwrap();
+        let published = sink.published();
+        let (last, steps) = published.split_last().unwrap();
+        assert!(steps
+            .iter()
+            .all(|(_, payload)| payload.contains(r#""type":"color""#)));
+        assert_eq!(
+            last.1,
+            format!(
+                r#"{{"type":"colorTemperature","dn":"{}","value":"16","time":1600000000000}}"#,
+                MAC
+            )
+        );
+    }
+
+    #[test]
+    fn fade_corrects_colors_once() {
+        let sink = MockSink::new();
+        let api = SengledApi::builder("", "")
+            .color_profile(ColorProfile {
+                gamma: 1.,
+                white_balance: (1., 0.5, 1.),
+            })
+            .fade_step(Duration::from_millis(1))
+            .build_with_sink(sink.clone())
+            .unwrap();
+        let device = device();
+        let green = Color::Rgb {
+            red: 0,
+            green: 200,
+            blue: 0,
+        };
+        smol::block_on(async {
+            api.set_color(&device, green).await.unwrap();
+            api.fade_color(&device, green, Duration::from_millis(4))
+                .await
+                .unwrap();
+        });
+        let published = sink.published();
+        assert!(published.len() > 2);
+        // Starting from the corrected color and correcting each step again would dim it.
+        assert!(published
+            .iter()
+            .all(|(_, payload)| payload.contains(r#""value":"0:100:0""#)));
+    }
+
+    #[test]
+    fn cancelled_send_keeps_its_place() {
+        let (api, _) = mock_api();
+        let dn: Mac = MAC.parse().unwrap();
+        let first = smol::block_on(api.queue(&dn));
+        let mut second = Box::pin(api.queue(&dn));
+        assert!((&mut second).now_or_never().is_none());
+        let mut third = Box::pin(api.queue(&dn));
+        assert!((&mut third).now_or_never().is_none());
+        // Dropping the waiting send must not let the one behind it past the first.
+        drop(second);
+        assert!((&mut third).now_or_never().is_none());
+        drop(first);
+        assert!(third.now_or_never().is_some());
+    }
+
+    #[test]
+    fn mac_methods_take_strings_and_macs() {
+        let (api, sink) = mock_api();
+        smol::block_on(async {
+            api.turn_on_mac(MAC).await.unwrap();
+            api.turn_on_mac(MAC.parse::<Mac>().unwrap()).await.unwrap();
+            assert!(matches!(
+                api.turn_on_mac("not a mac").await,
+                Err(Error::InvalidMac(_))
+            ));
+        });
+        assert_eq!(sink.published().len(), 2);
+    }
+
+    #[test]
+    fn status_topic_follows_template() {
+        let api = SengledApi::builder("", "")
+            .status_topic_template("hub/{mac}/status")
+            .build_with_sink(MockSink::new())
+            .unwrap();
+        // Registering the subscriber happens before anything is sent to the broker.
+        let _ = api.subscribe_state(&device()).now_or_never();
+        let topics: Vec<_> = api
+            .subscribers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(topic, _)| topic.clone())
+            .collect();
+        assert_eq!(topics, vec![format!("hub/{}/status", MAC)]);
+    }
+
+    #[test]
+    fn command_helpers_follow_configuration() {
+        let api = SengledApi::builder("", "")
+            .topic_template("hub/{mac}/set")
+            .clock(FixedClock(1_600_000_000_000))
+            .build_with_sink(MockSink::new())
+            .unwrap();
+        let command = Command::switch(&device(), true);
+        assert_eq!(api.command_topic(&command), format!("hub/{}/set", MAC));
+        assert_eq!(
+            api.command_json(&command).unwrap(),
+            format!(
+                r#"{{"type":"switch","dn":"{}","value":"1","time":1600000000000}}"#,
+                MAC
+            )
+        );
+    }
+
+    #[test]
+    fn commit_batch_reports_each_device() {
+        let sink = MockSink::new();
+        let api = SengledApi::builder("", "")
+            .offline_policy(OfflinePolicy::Error)
+            .build_with_sink(sink.clone())
+            .unwrap();
+        let offline: Mac = "B0:CE:18:00:00:02".parse().unwrap();
+        api.online.lock().unwrap().insert(offline.clone(), false);
+        let result = smol::block_on(async {
+            api.begin_batch();
+            api.turn_on(&device()).await.unwrap();
+            api.set_brightness(&device(), Brightness::from_percent(50).unwrap())
+                .await
+                .unwrap();
+            api.turn_on_mac(&offline).await.unwrap();
+            api.commit_batch().await
+        });
+        let results = result.results();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].0, MAC.parse::<Mac>().unwrap());
+        assert!(results[0].1.is_ok());
+        assert_eq!(results[1].0, offline);
+        assert!(matches!(results[1].1, Err(Error::DeviceOffline(_))));
+        assert_eq!(sink.published().len(), 1);
+    }
+
+    struct FailingSink;
+
+    impl CommandSink for FailingSink {
+        fn publish(&self, _: String, _: String, _: Qos) -> BoxFuture<'static, Result<(), Error>> {
+            Box::pin(future::ready(Err(Error::Mqtt(mqtt::Error::General(
+                "rejected",
+            )))))
+        }
+    }
+
+    #[test]
+    fn history_keeps_only_published_commands() {
+        fn recorded<S: CommandSink + 'static>(sink: S) -> Vec<SentCommand> {
+            let api = SengledApi::builder("", "")
+                .command_history(4)
+                .build_with_sink(sink)
+                .unwrap();
+            let _ = smol::block_on(api.turn_on(&device()));
+            api.recent_commands()
+        }
+        assert!(recorded(FailingSink).is_empty());
+        let history = recorded(MockSink::new());
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].ty, CommandType::Switch);
+    }
+
+    #[test]
+    fn device_round_trips_through_json() {
+        let device: Device = serde_json::from_str(&format!(
+            r#"{{"deviceUuid":"{}","attributeList":[{{"name":"brightness","value":"40"}},{{"name":"online","value":"0"}},{{"name":"roomName","value":"Den"}}]}}"#,
+            MAC
+        ))
+        .unwrap();
+        // Without a name attribute the MAC stands in, and must survive the round trip.
+        assert_eq!(device.name, MAC);
+        let json = serde_json::to_string(&device).unwrap();
+        let restored: Device = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.uuid(), device.uuid());
+        assert_eq!(restored.name, MAC);
+        assert!(!restored.online);
+        assert_eq!(restored.room.as_deref(), Some("Den"));
+        assert_eq!(
+            restored.attributes().get("brightness").map(String::as_str),
+            Some("40")
+        );
+        assert_eq!(serde_json::to_string(&restored).unwrap(), json);
     }
 }
@@ -9,20 +9,13 @@ fn main() {
         )
         .await
         .unwrap();
-        let devices = api.get_devices().await.unwrap();
-        let device = devices
-            .into_iter()
-            .find(|device| device.name == "Sparkle")
-            .unwrap();
-        loop {
-            api.set_color(&device, Color::White { temperature: 2700 })
-                .await
-                .unwrap();
-            smol::Timer::after(std::time::Duration::from_millis(200)).await;
-            api.set_color(&device, Color::White { temperature: 6500 })
-                .await
-                .unwrap();
-            smol::Timer::after(std::time::Duration::from_millis(200)).await;
-        }
+        let device = api.get_device_by_name("Sparkle").await.unwrap().unwrap();
+        api.pulse(
+            &device,
+            &[Color::WARM_WHITE, Color::COOL_WHITE],
+            std::time::Duration::from_millis(200),
+        )
+        .await
+        .unwrap();
     });
 }
@@ -0,0 +1,222 @@
+//! A wrapper around [`SengledApi`] for long-running deployments that would rather wait out
+//! outages than handle them. A supervisor task owns the connection, logging in and reconnecting
+//! whenever it fails, and works through requests in the order they were made.
+//!
+//! Only available with the `runtime-async-std` HTTP backend, as the supervisor thread has no
+//! tokio reactor.
+
+use crate::{mqtt, Brightness, Color, Device, Error, Region, SengledApi, SengledApiBuilder};
+use futures::{
+    channel::{mpsc, oneshot},
+    future::LocalBoxFuture,
+    FutureExt, StreamExt,
+};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Backoff between reconnection attempts starts at this and doubles up to `MAX_BACKOFF`.
+const BASE_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+struct Request {
+    attempt: Box<dyn FnMut(SengledApi) -> LocalBoxFuture<'static, Result<(), Error>> + Send>,
+    fail: Box<dyn FnOnce(Error) + Send>,
+}
+
+/// Like [`SengledApi`], but transient failures (network errors, timeouts, a dropped MQTT
+/// connection) are never returned: the supervisor reconnects with backoff and tries again, and
+/// calls made in the meantime are queued. Only errors retrying can't fix, such as rejected
+/// credentials or an RGB color sent to a white-only bulb, are returned.
+///
+/// Requests are carried out one at a time in the order they were made. Dropping a call's
+/// future doesn't withdraw it. The supervisor runs on its own thread, shared by all clones, and
+/// exits once the last clone is dropped and the queue is empty.
+#[derive(Clone)]
+pub struct ReliableSengledApi {
+    requests: mpsc::UnboundedSender<Request>,
+}
+
+impl ReliableSengledApi {
+    /// Logs in lazily, when the first request is made.
+    pub fn new<T: AsRef<str>, U: AsRef<str>>(user: T, pass: U) -> Self {
+        Self::new_with_region(user, pass, Region::default())
+    }
+    pub fn new_with_region<T: AsRef<str>, U: AsRef<str>>(user: T, pass: U, region: Region) -> Self {
+        let (user, pass) = (user.as_ref().to_owned(), pass.as_ref().to_owned());
+        Self::from_builder(move || SengledApi::builder(&user, &pass).region(region.clone()))
+    }
+    /// Connects with a fresh builder from `make` every time the connection has to be
+    /// re-established, for control over everything `SengledApiBuilder` offers.
+    pub fn from_builder<F: Fn() -> SengledApiBuilder + Send + 'static>(make: F) -> Self {
+        let (requests, receiver) = mpsc::unbounded();
+        // The builder isn't `Send`, so connecting happens entirely on the supervisor's thread.
+        thread::spawn(move || smol::block_on(supervise(make, receiver)));
+        ReliableSengledApi { requests }
+    }
+    /// Queues `f` to run against the current connection, running it again on a new connection
+    /// for as long as it fails transiently.
+    async fn run<T, F, Fut>(&self, mut f: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnMut(SengledApi) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<T, Error>> + 'static,
+    {
+        let (reply, result) = oneshot::channel();
+        let reply = Arc::new(Mutex::new(Some(reply)));
+        let finish = reply.clone();
+        let request = Request {
+            attempt: Box::new(move |api| {
+                let attempt = f(api);
+                let reply = reply.clone();
+                async move {
+                    let value = attempt.await?;
+                    if let Some(reply) = reply.lock().unwrap().take() {
+                        let _ = reply.send(Ok(value));
+                    }
+                    Ok(())
+                }
+                .boxed_local()
+            }),
+            fail: Box::new(move |error| {
+                if let Some(reply) = finish.lock().unwrap().take() {
+                    let _ = reply.send(Err(error));
+                }
+            }),
+        };
+        self.requests
+            .unbounded_send(request)
+            .expect("supervisor outlives its handles");
+        result.await.expect("supervisor answers every request")
+    }
+    pub async fn get_devices(&self) -> Result<Vec<Device>, Error> {
+        self.run(|api| async move { api.get_devices().await }).await
+    }
+    pub async fn set_switch(&self, device: &Device, on: bool) -> Result<(), Error> {
+        let device = device.clone();
+        self.run(move |api| {
+            let device = device.clone();
+            async move { api.set_switch(&device, on).await }
+        })
+        .await
+    }
+    pub async fn turn_on(&self, device: &Device) -> Result<(), Error> {
+        self.set_switch(device, true).await
+    }
+    pub async fn turn_off(&self, device: &Device) -> Result<(), Error> {
+        self.set_switch(device, false).await
+    }
+    pub async fn toggle(&self, device: &Device) -> Result<bool, Error> {
+        let device = device.clone();
+        self.run(move |api| {
+            let device = device.clone();
+            async move { api.toggle(&device).await }
+        })
+        .await
+    }
+    pub async fn set_brightness(
+        &self,
+        device: &Device,
+        brightness: Brightness,
+    ) -> Result<(), Error> {
+        let device = device.clone();
+        self.run(move |api| {
+            let device = device.clone();
+            async move { api.set_brightness(&device, brightness).await }
+        })
+        .await
+    }
+    pub async fn set_color(&self, device: &Device, color: Color) -> Result<(), Error> {
+        let device = device.clone();
+        self.run(move |api| {
+            let device = device.clone();
+            async move { api.set_color(&device, color).await }
+        })
+        .await
+    }
+    pub async fn set_color_temperature(&self, device: &Device, kelvin: u16) -> Result<(), Error> {
+        let device = device.clone();
+        self.run(move |api| {
+            let device = device.clone();
+            async move { api.set_color_temperature(&device, kelvin).await }
+        })
+        .await
+    }
+}
+
+/// Whether waiting and reconnecting might fix `error`. Failing to reach the MQTT broker counts,
+/// unlike for a single call, but the broker refusing the connection doesn't.
+fn is_transient(error: &Error) -> bool {
+    match error {
+        Error::Connect {
+            source: mqtt::Error::Paho(code) | mqtt::Error::PahoDescr(code, _),
+            ..
+        } => *code <= 0,
+        Error::Connect { .. } => true,
+        error => error.is_retryable(),
+    }
+}
+
+fn backoff(failures: u32) -> Duration {
+    BASE_BACKOFF
+        .saturating_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+        .min(MAX_BACKOFF)
+}
+
+async fn supervise<F: Fn() -> SengledApiBuilder>(
+    make: F,
+    mut requests: mpsc::UnboundedReceiver<Request>,
+) {
+    let mut api: Option<SengledApi> = None;
+    let mut failures = 0;
+    while let Some(mut request) = requests.next().await {
+        loop {
+            let current = match &api {
+                Some(current) => current.clone(),
+                None => match make().connect().await {
+                    Ok(connected) => {
+                        log::info!("connected to the Sengled cloud");
+                        api = Some(connected.clone());
+                        connected
+                    }
+                    Err(e) if is_transient(&e) => {
+                        failures += 1;
+                        log::warn!(
+                            "connecting failed, retrying in {:?}: {}",
+                            backoff(failures),
+                            e
+                        );
+                        smol::Timer::after(backoff(failures)).await;
+                        continue;
+                    }
+                    Err(e) => {
+                        log::error!("connecting failed: {}", e);
+                        (request.fail)(e);
+                        break;
+                    }
+                },
+            };
+            match (request.attempt)(current).await {
+                Ok(()) => {
+                    failures = 0;
+                    break;
+                }
+                Err(e) if is_transient(&e) => {
+                    failures += 1;
+                    log::warn!(
+                        "request failed, reconnecting in {:?}: {}",
+                        backoff(failures),
+                        e
+                    );
+                    api = None;
+                    smol::Timer::after(backoff(failures)).await;
+                }
+                Err(e) => {
+                    (request.fail)(e);
+                    break;
+                }
+            }
+        }
+    }
+}